@@ -0,0 +1,491 @@
+use std::collections::HashMap;
+use crate::filter::Mode;
+use crate::midi::{GMFamily, GMInstrument};
+use crate::osc::Shape;
+use crate::synth::Preset;
+
+/// Bank selected by Bank Select MSB (CC0) and LSB (CC32) controllers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Bank {
+    pub msb: u8,
+    pub lsb: u8,
+}
+
+impl Bank {
+    /// Bank of the General MIDI capital tones.
+    pub const CAPITAL: Bank = Bank { msb: 0, lsb: 0 };
+
+    pub fn new(msb: u8, lsb: u8) -> Self {
+        Bank { msb, lsb }
+    }
+}
+
+/// Presets addressed by `(bank, program)` pairs. The capital bank holds
+/// presets approximating the 128 General MIDI programs, other banks hold
+/// variations of them.
+pub struct PresetBank {
+    capital: Vec<Preset>,
+    variations: HashMap<(Bank, u8), Preset>,
+}
+
+impl PresetBank {
+    /// Creates bank with General MIDI capital tones and GS style
+    /// variations in banks 8 (detuned) and 16 (bright).
+    pub fn gm() -> Self {
+        let mut bank = PresetBank {
+            capital: (0..128)
+                .map(|program| gm_preset(GMInstrument::new(program).unwrap()))
+                .collect(),
+            variations: HashMap::new(),
+        };
+
+        for program in 0..128 {
+            let capital = bank.capital[program as usize];
+            bank.insert(Bank::new(8, 0), program, Preset {
+                osc2_tuning: capital.osc2_tuning + 0.15,
+                osc1_pitch_mod: capital.osc1_pitch_mod + 0.02,
+                ..capital
+            });
+            bank.insert(Bank::new(16, 0), program, Preset {
+                filter_cutoff: (capital.filter_cutoff * 1.8).min(0.95),
+                filter_resonance: (capital.filter_resonance + 0.2).min(0.9),
+                ..capital
+            });
+        }
+
+        bank
+    }
+
+    pub fn insert(&mut self, bank: Bank, program: u8, preset: Preset) {
+        if bank == Bank::CAPITAL {
+            self.capital[program as usize & 0x7f] = preset;
+        } else {
+            self.variations.insert((bank, program), preset);
+        }
+    }
+
+    /// Resolves preset of the program in specified bank. Missing variation
+    /// falls back to bank with the same MSB (LSB often selects only the
+    /// sound map in GS) and then to the capital tone.
+    pub fn get(&self, bank: Bank, instrument: GMInstrument) -> &Preset {
+        let program = instrument.program_number();
+
+        self.variations.get(&(bank, program))
+            .or_else(|| self.variations.get(&(Bank::new(bank.msb, 0), program)))
+            .unwrap_or(&self.capital[program as usize])
+    }
+}
+
+/// Neutral preset the family presets are derived from.
+fn base() -> Preset {
+    Preset {
+        osc1_waveform: Shape::Saw,
+        osc2_waveform: Shape::Saw,
+        osc1_pitch_mod: 0.0,
+        osc2_pitch_mod: 0.0,
+        osc1_tuning: 0.0,
+        osc2_tuning: 0.0,
+        osc_mix: 0.5,
+        attack: 0.01,
+        decay: 0.3,
+        sustain: 0.7,
+        release: 0.3,
+        filter_mode: Mode::Lowpass,
+        filter_cutoff: 0.15,
+        filter_resonance: 0.1,
+        filter_attack: 0.01,
+        filter_decay: 0.3,
+        filter_sustain: 0.5,
+        filter_release: 0.3,
+        filter_evn_amount: 0.2,
+        lfo_waveform: Shape::Sine,
+        lfo_frequency: 5.0,
+        lfo_filter_mod_amount: 0.0,
+        pressure_cutoff: 0.2,
+        pressure_amplitude: 0.3,
+        pressure_pitch_mod: 0.3,
+        key_pan_spread: 0.0,
+        voice_spread: 0.0,
+    }
+}
+
+fn family_preset(family: GMFamily) -> Preset {
+    match family {
+        GMFamily::Piano => Preset {
+            osc1_waveform: Shape::Triangle,
+            osc2_waveform: Shape::Saw,
+            osc_mix: 0.3,
+            attack: 0.005,
+            decay: 1.5,
+            sustain: 0.15,
+            release: 0.4,
+            filter_cutoff: 0.12,
+            filter_attack: 0.002,
+            filter_decay: 0.8,
+            filter_sustain: 0.1,
+            filter_evn_amount: 0.4,
+            key_pan_spread: 0.4,
+            ..base()
+        },
+        GMFamily::ChromaticPercussion => Preset {
+            osc1_waveform: Shape::Sine,
+            osc2_waveform: Shape::Triangle,
+            osc2_tuning: 12.0,
+            osc_mix: 0.3,
+            attack: 0.002,
+            decay: 0.8,
+            sustain: 0.01,
+            release: 0.6,
+            filter_cutoff: 0.5,
+            filter_evn_amount: 0.0,
+            key_pan_spread: 0.3,
+            ..base()
+        },
+        GMFamily::Organ => Preset {
+            osc1_waveform: Shape::Square,
+            osc2_waveform: Shape::Sine,
+            osc2_tuning: -12.0,
+            attack: 0.01,
+            decay: 0.1,
+            sustain: 1.0,
+            release: 0.08,
+            filter_cutoff: 0.3,
+            filter_evn_amount: 0.0,
+            lfo_frequency: 6.0,
+            osc1_pitch_mod: 0.02,
+            ..base()
+        },
+        GMFamily::Guitar => Preset {
+            osc1_waveform: Shape::Saw,
+            osc2_waveform: Shape::Square,
+            osc_mix: 0.3,
+            attack: 0.003,
+            decay: 1.2,
+            sustain: 0.1,
+            release: 0.3,
+            filter_cutoff: 0.1,
+            filter_attack: 0.002,
+            filter_decay: 0.3,
+            filter_sustain: 0.2,
+            filter_evn_amount: 0.5,
+            ..base()
+        },
+        GMFamily::Bass => Preset {
+            osc1_waveform: Shape::Saw,
+            osc2_waveform: Shape::Square,
+            osc2_tuning: -12.0,
+            attack: 0.005,
+            decay: 0.5,
+            sustain: 0.5,
+            release: 0.15,
+            filter_cutoff: 0.06,
+            filter_resonance: 0.3,
+            filter_decay: 0.2,
+            filter_sustain: 0.2,
+            filter_evn_amount: 0.3,
+            ..base()
+        },
+        GMFamily::Strings => Preset {
+            osc2_tuning: 0.1,
+            attack: 0.15,
+            decay: 0.3,
+            sustain: 0.9,
+            release: 0.5,
+            filter_cutoff: 0.12,
+            filter_evn_amount: 0.1,
+            osc1_pitch_mod: 0.03,
+            osc2_pitch_mod: 0.03,
+            key_pan_spread: 0.2,
+            voice_spread: 0.15,
+            ..base()
+        },
+        GMFamily::Ensemble => Preset {
+            osc2_tuning: -0.12,
+            attack: 0.3,
+            decay: 0.5,
+            sustain: 0.9,
+            release: 0.8,
+            filter_cutoff: 0.1,
+            filter_evn_amount: 0.05,
+            voice_spread: 0.25,
+            ..base()
+        },
+        GMFamily::Brass => Preset {
+            osc2_tuning: 0.05,
+            attack: 0.05,
+            decay: 0.2,
+            sustain: 0.8,
+            release: 0.2,
+            filter_cutoff: 0.06,
+            filter_attack: 0.08,
+            filter_decay: 0.2,
+            filter_sustain: 0.6,
+            filter_evn_amount: 0.4,
+            ..base()
+        },
+        GMFamily::Reed => Preset {
+            osc1_waveform: Shape::Square,
+            osc2_waveform: Shape::Saw,
+            osc_mix: 0.3,
+            attack: 0.03,
+            decay: 0.2,
+            sustain: 0.9,
+            release: 0.1,
+            filter_cutoff: 0.1,
+            filter_resonance: 0.4,
+            filter_evn_amount: 0.15,
+            ..base()
+        },
+        GMFamily::Pipe => Preset {
+            osc1_waveform: Shape::Triangle,
+            osc2_waveform: Shape::Sine,
+            osc2_tuning: 12.0,
+            osc_mix: 0.2,
+            attack: 0.06,
+            decay: 0.2,
+            sustain: 0.9,
+            release: 0.2,
+            filter_cutoff: 0.2,
+            filter_evn_amount: 0.05,
+            osc1_pitch_mod: 0.02,
+            ..base()
+        },
+        GMFamily::SynthLead => Preset {
+            osc1_waveform: Shape::Saw,
+            osc2_waveform: Shape::Square,
+            osc2_tuning: 0.07,
+            attack: 0.005,
+            decay: 0.3,
+            sustain: 0.8,
+            release: 0.2,
+            filter_cutoff: 0.2,
+            filter_resonance: 0.5,
+            filter_evn_amount: 0.3,
+            ..base()
+        },
+        GMFamily::SynthPad => Preset {
+            osc2_waveform: Shape::Triangle,
+            osc2_tuning: 0.08,
+            attack: 0.6,
+            decay: 1.0,
+            sustain: 0.8,
+            release: 1.5,
+            filter_cutoff: 0.08,
+            filter_attack: 0.8,
+            filter_release: 1.5,
+            lfo_frequency: 0.5,
+            lfo_filter_mod_amount: 0.05,
+            voice_spread: 0.3,
+            ..base()
+        },
+        GMFamily::SynthEffects => Preset {
+            osc1_waveform: Shape::Square,
+            osc2_tuning: 12.0,
+            attack: 0.2,
+            decay: 1.0,
+            sustain: 0.6,
+            release: 2.0,
+            filter_mode: Mode::Bandpass,
+            filter_cutoff: 0.2,
+            filter_resonance: 0.5,
+            lfo_frequency: 0.3,
+            lfo_filter_mod_amount: 0.1,
+            ..base()
+        },
+        GMFamily::Ethnic => Preset {
+            osc1_waveform: Shape::Triangle,
+            osc_mix: 0.4,
+            attack: 0.002,
+            decay: 0.8,
+            sustain: 0.1,
+            release: 0.4,
+            filter_cutoff: 0.2,
+            filter_decay: 0.2,
+            filter_sustain: 0.2,
+            filter_evn_amount: 0.4,
+            ..base()
+        },
+        GMFamily::Percussive => Preset {
+            osc1_waveform: Shape::Sine,
+            osc2_waveform: Shape::Triangle,
+            osc2_tuning: 7.0,
+            osc_mix: 0.3,
+            attack: 0.001,
+            decay: 0.4,
+            sustain: 0.01,
+            release: 0.3,
+            filter_cutoff: 0.4,
+            filter_evn_amount: 0.0,
+            ..base()
+        },
+        GMFamily::SoundEffects => Preset {
+            osc1_waveform: Shape::Noise,
+            osc2_waveform: Shape::Saw,
+            osc_mix: 0.3,
+            attack: 0.1,
+            decay: 0.5,
+            sustain: 0.7,
+            release: 1.0,
+            filter_mode: Mode::Bandpass,
+            filter_cutoff: 0.3,
+            filter_evn_amount: 0.1,
+            lfo_frequency: 2.0,
+            lfo_filter_mod_amount: 0.1,
+            ..base()
+        },
+    }
+}
+
+/// Returns the preset of a General MIDI program: the family preset with
+/// generic per-program variation and overrides for characteristic programs.
+pub fn gm_preset(instrument: GMInstrument) -> Preset {
+    let program = instrument.program_number();
+    let mut preset = family_preset(instrument.family());
+
+    /* brightness, detune and length vary across the family */
+    let index = (program % 8) as f64;
+    preset.filter_cutoff = (preset.filter_cutoff * (0.8 + 0.1 * index)).min(0.95);
+    preset.osc2_tuning += [0.0, 0.03, -0.03, 0.06, -0.06, 0.1, -0.1, 0.0][program as usize % 8];
+    preset.release *= 0.85 + 0.05 * index;
+
+    match program {
+        4 | 5 => Preset { /* Electric Piano 1, 2 */
+            osc1_waveform: Shape::Sine,
+            osc2_waveform: Shape::Sine,
+            osc2_tuning: 12.0,
+            osc_mix: 0.25,
+            lfo_frequency: 4.0,
+            osc1_pitch_mod: 0.02,
+            ..preset
+        },
+        6 | 7 => Preset { /* Harpsichord, Clavi */
+            osc1_waveform: Shape::Square,
+            decay: 0.8,
+            sustain: 0.05,
+            filter_cutoff: 0.35,
+            ..preset
+        },
+        19 | 20 => Preset { /* Church Organ, Reed Organ */
+            osc2_waveform: Shape::Saw,
+            osc2_tuning: 12.0,
+            attack: 0.08,
+            release: 0.6,
+            ..preset
+        },
+        29 | 30 => Preset { /* Overdriven Guitar, Distortion Guitar */
+            osc1_waveform: Shape::Square,
+            osc2_tuning: 7.0,
+            sustain: 0.8,
+            filter_cutoff: 0.25,
+            filter_resonance: 0.4,
+            ..preset
+        },
+        32..=34 => Preset { /* Acoustic Bass, Electric Bass (finger, pick) */
+            osc1_waveform: Shape::Triangle,
+            decay: 0.8,
+            sustain: 0.2,
+            ..preset
+        },
+        38 | 39 => Preset { /* Synth Bass 1, 2 */
+            filter_resonance: 0.6,
+            filter_evn_amount: 0.5,
+            ..preset
+        },
+        45 => Preset { /* Pizzicato Strings */
+            attack: 0.003,
+            decay: 0.3,
+            sustain: 0.01,
+            release: 0.2,
+            ..preset
+        },
+        46 => Preset { /* Orchestral Harp */
+            osc1_waveform: Shape::Triangle,
+            osc2_waveform: Shape::Sine,
+            attack: 0.002,
+            decay: 1.5,
+            sustain: 0.05,
+            release: 1.0,
+            ..preset
+        },
+        47 => Preset { /* Timpani */
+            osc1_waveform: Shape::Sine,
+            osc2_waveform: Shape::Triangle,
+            osc2_tuning: -12.0,
+            attack: 0.002,
+            decay: 1.2,
+            sustain: 0.01,
+            release: 1.0,
+            ..preset
+        },
+        52..=54 => Preset { /* Choir Aahs, Voice Oohs, Synth Voice */
+            osc1_waveform: Shape::Triangle,
+            filter_mode: Mode::Bandpass,
+            filter_cutoff: 0.15,
+            filter_resonance: 0.3,
+            ..preset
+        },
+        55 => Preset { /* Orchestra Hit */
+            attack: 0.002,
+            decay: 0.3,
+            sustain: 0.01,
+            release: 0.3,
+            ..preset
+        },
+        57 | 58 => Preset { /* Trombone, Tuba */
+            osc2_tuning: -12.0,
+            ..preset
+        },
+        68..=71 => Preset { /* Oboe, English Horn, Bassoon, Clarinet */
+            osc1_waveform: Shape::Square,
+            osc2_waveform: Shape::Square,
+            osc2_tuning: 0.0,
+            ..preset
+        },
+        72..=79 => Preset { /* flutes and whistles are mostly pure tones */
+            osc1_waveform: Shape::Sine,
+            pressure_pitch_mod: 0.5,
+            ..preset
+        },
+        80 => Preset { /* Lead 1 (square) */
+            osc2_waveform: Shape::Square,
+            osc1_waveform: Shape::Square,
+            ..preset
+        },
+        81 => Preset { /* Lead 2 (sawtooth) */
+            osc2_waveform: Shape::Saw,
+            ..preset
+        },
+        112..=115 => Preset { /* Tinkle Bell, Agogo, Steel Drums, Woodblock */
+            osc2_tuning: 19.0,
+            ..preset
+        },
+        116..=118 => Preset { /* Taiko Drum, Melodic Tom, Synth Drum */
+            osc2_tuning: -12.0,
+            decay: 0.6,
+            ..preset
+        },
+        _ => preset,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bank::{Bank, PresetBank};
+    use crate::midi::GMInstrument;
+
+    #[test]
+    fn variations_and_fallback() {
+        let bank = PresetBank::gm();
+        let piano = GMInstrument::new(0).unwrap();
+        let capital = bank.get(Bank::CAPITAL, piano);
+
+        /* programs of different families sound different */
+        let bass = bank.get(Bank::CAPITAL, GMInstrument::new(33).unwrap());
+        assert_ne!((capital.decay, capital.sustain, capital.filter_cutoff), (bass.decay, bass.sustain, bass.filter_cutoff));
+
+        /* LSB falls back to the MSB bank, unknown banks to the capital tone */
+        let detuned = bank.get(Bank::new(8, 3), piano);
+        assert_eq!(detuned.osc2_tuning, capital.osc2_tuning + 0.15);
+        assert_eq!(bank.get(Bank::new(5, 0), piano).osc2_tuning, capital.osc2_tuning);
+    }
+}
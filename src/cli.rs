@@ -0,0 +1,461 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::str::FromStr;
+use crate::bank::Bank;
+use crate::midi::{GMInstrument, Midi, MidiPlayback, Player, DEFAULT_VOICE_LIMIT};
+use crate::synth::{StealPolicy, DEFAULT_POLYPHONY};
+use crate::control::parse_time;
+
+pub fn app() -> App<'static, 'static> {
+    App::new("mod_tracker")
+        .version(crate_version!())
+        .about("Plays and renders midi files with a built-in synthesizer")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("play")
+            .about("Plays midi file on an audio device")
+            .args(&file_args())
+            .args(&playback_args())
+            .arg(Arg::with_name("device")
+                .long("device")
+                .short("d")
+                .value_name("NAME|INDEX")
+                .help("Output device from the `devices` list, the default device is used if not specified"))
+            .arg(Arg::with_name("device-format")
+                .long("device-format")
+                .value_name("INDEX")
+                .validator(number::<usize>)
+                .conflicts_with_all(&["channels", "sample-format"])
+                .help("Output format of the device from the `devices` list"))
+            .arg(Arg::with_name("channels")
+                .long("channels")
+                .value_name("COUNT")
+                .validator(number::<u16>)
+                .help("Number of channels of the output stream"))
+            .arg(Arg::with_name("sample-format")
+                .long("sample-format")
+                .value_name("FORMAT")
+                .possible_values(&["f32", "i16", "u16"])
+                .help("Sample format of the output stream"))
+            .arg(Arg::with_name("buffer-size")
+                .long("buffer-size")
+                .value_name("FRAMES")
+                .validator(number::<u32>)
+                .help("Requested buffer size of the audio device, not supported by the audio backend yet"))
+            .arg(Arg::with_name("no-lyrics")
+                .long("no-lyrics")
+                .help("Does not display lyrics of karaoke files"))
+            .arg(Arg::with_name("loop")
+                .long("loop")
+                .value_names(&["A", "B"])
+                .number_of_values(2)
+                .validator(time)
+                .help("Plays section between two times repeatedly")))
+        .subcommand(SubCommand::with_name("render")
+            .about("Renders midi file to a WAV file")
+            .args(&file_args())
+            .args(&playback_args())
+            .arg(Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .value_name("FILE")
+                .required(true)
+                .help("Output WAV file"))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&["pcm16", "pcm24", "float32"])
+                .default_value("pcm16")
+                .help("Sample format of the output file"))
+            .arg(Arg::with_name("mono")
+                .long("mono")
+                .help("Renders single channel file")))
+        .subcommand(SubCommand::with_name("devices")
+            .about("Lists output devices and their supported formats"))
+        .subcommand(SubCommand::with_name("info")
+            .about("Prints information about midi file")
+            .args(&file_args()))
+}
+
+fn file_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("FILE")
+            .required(true)
+            .index(1)
+            .help("Midi file"),
+        Arg::with_name("lenient")
+            .long("lenient")
+            .help("Loads as much as possible from malformed files"),
+    ]
+}
+
+fn playback_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("sample-rate")
+            .long("sample-rate")
+            .short("r")
+            .value_name("HZ")
+            .validator(number::<u32>)
+            .help("Sample rate of the output"),
+        Arg::with_name("preset")
+            .long("preset")
+            .short("p")
+            .value_name("PROGRAM")
+            .validator(program)
+            .conflicts_with("random-presets")
+            .help("Plays all melodic channels with preset of specified GM program (0-127)"),
+        Arg::with_name("random-presets")
+            .long("random-presets")
+            .help("Plays all melodic channels with random presets"),
+        Arg::with_name("transpose")
+            .long("transpose")
+            .short("t")
+            .value_name("SEMITONES")
+            .allow_hyphen_values(true)
+            .validator(transpose)
+            .help("Transposes melodic channels by specified number of semitones"),
+        Arg::with_name("channel-transpose")
+            .long("channel-transpose")
+            .value_name("CHANNEL=SEMITONES")
+            .multiple(true)
+            .number_of_values(1)
+            .allow_hyphen_values(true)
+            .validator(channel_transpose)
+            .help("Transposes midi channel (1-16) on top of --transpose, can be used multiple times"),
+        Arg::with_name("tempo")
+            .long("tempo")
+            .value_name("SCALE")
+            .validator(positive)
+            .help("Plays the song faster (> 1.0) or slower (< 1.0)"),
+        Arg::with_name("gain")
+            .long("gain")
+            .short("g")
+            .value_name("DB")
+            .allow_hyphen_values(true)
+            .validator(number::<f64>)
+            .help("Master gain in decibels (default -6)"),
+        Arg::with_name("channel-gain")
+            .long("channel-gain")
+            .value_name("CHANNEL=DB")
+            .multiple(true)
+            .number_of_values(1)
+            .allow_hyphen_values(true)
+            .validator(channel_gain)
+            .help("Gain of midi channel (1-16) in decibels, can be used multiple times"),
+        Arg::with_name("mute")
+            .long("mute")
+            .value_name("CHANNEL")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(channel)
+            .help("Mutes midi channel (1-16), can be used multiple times"),
+        Arg::with_name("solo")
+            .long("solo")
+            .value_name("CHANNEL")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(channel)
+            .help("Plays only specified midi channels (1-16), can be used multiple times"),
+        Arg::with_name("mute-track")
+            .long("mute-track")
+            .value_name("TRACK")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(number::<usize>)
+            .help("Mutes track (numbered as in `info`), can be used multiple times"),
+        Arg::with_name("solo-track")
+            .long("solo-track")
+            .value_name("TRACK")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(number::<usize>)
+            .help("Plays only specified tracks (numbered as in `info`), can be used multiple times"),
+        Arg::with_name("polyphony")
+            .long("polyphony")
+            .value_name("VOICES")
+            .validator(positive_number)
+            .help("Number of voices of each melodic channel (default 128)"),
+        Arg::with_name("steal-policy")
+            .long("steal-policy")
+            .value_name("POLICY")
+            .possible_values(&["oldest", "quietest", "lowest-velocity", "same-note"])
+            .help("Voice reused when a channel runs out of voices (default oldest)"),
+        Arg::with_name("voices")
+            .long("voices")
+            .value_name("COUNT")
+            .validator(positive_number)
+            .help("Number of voices playing at once across all channels (default 256)"),
+        Arg::with_name("priority")
+            .long("priority")
+            .value_name("CHANNEL=PRIORITY")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(channel_priority)
+            .help("Priority (0-127, default 64) of midi channel (1-16) when voices run out, lower priority channels give up voices first, can be used multiple times"),
+        Arg::with_name("reserve")
+            .long("reserve")
+            .value_name("CHANNEL=VOICES")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(channel_reserve)
+            .help("Voices of midi channel (1-16) which other channels never take, can be used multiple times"),
+        Arg::with_name("no-limiter")
+            .long("no-limiter")
+            .help("Disables the limiter on the master bus"),
+        Arg::with_name("pattern")
+            .long("pattern")
+            .value_name("INDEX")
+            .validator(number::<usize>)
+            .help("Plays only one pattern of a format 2 file (numbered as tracks in `info`)"),
+        Arg::with_name("start")
+            .long("start")
+            .short("s")
+            .value_name("TIME")
+            .validator(time)
+            .help("Starts playback at specified position in the song (seconds or m:ss)"),
+    ]
+}
+
+fn number<T: FromStr>(value: String) -> Result<(), String> {
+    value.parse::<T>()
+        .map(|_| ())
+        .map_err(|_| format!("'{}' is not a valid number", value))
+}
+
+fn positive_number(value: String) -> Result<(), String> {
+    match value.parse::<usize>() {
+        Ok(x) if x > 0 => Ok(()),
+        _ => Err(format!("'{}' is not a positive whole number", value)),
+    }
+}
+
+fn program(value: String) -> Result<(), String> {
+    match value.parse::<u8>() {
+        Ok(x) if x < 128 => Ok(()),
+        _ => Err(format!("'{}' is not a program number (0-127)", value)),
+    }
+}
+
+fn transpose(value: String) -> Result<(), String> {
+    match value.parse::<i8>() {
+        Ok(x) if (-48..=48).contains(&x) => Ok(()),
+        _ => Err(format!("'{}' is not a transposition (-48 to 48 semitones)", value)),
+    }
+}
+
+/// Parses midi channel numbered from 1 into its index.
+fn parse_channel(value: &str) -> Option<u8> {
+    value.parse::<u8>().ok().filter(|x| (1..=16).contains(x)).map(|x| x - 1)
+}
+
+fn channel(value: String) -> Result<(), String> {
+    parse_channel(&value)
+        .map(|_| ())
+        .ok_or_else(|| format!("'{}' is not a midi channel (1-16)", value))
+}
+
+/// Parses `CHANNEL=VALUE` with channel numbered from 1 into `(index, value)`.
+fn parse_channel_value<T: FromStr>(value: &str) -> Option<(u8, T)> {
+    let mut parts = value.splitn(2, '=');
+    let channel = parse_channel(parts.next()?)?;
+    let value = parts.next()?.parse::<T>().ok()?;
+    Some((channel, value))
+}
+
+fn parse_channel_gain(value: &str) -> Option<(u8, f64)> {
+    parse_channel_value(value)
+}
+
+fn parse_channel_transpose(value: &str) -> Option<(u8, i8)> {
+    parse_channel_value(value).filter(|&(_, x): &(u8, i8)| (-48..=48).contains(&x))
+}
+
+fn channel_transpose(value: String) -> Result<(), String> {
+    parse_channel_transpose(&value)
+        .map(|_| ())
+        .ok_or_else(|| format!("'{}' is not a channel transposition (e.g. 2=-12)", value))
+}
+
+fn parse_channel_priority(value: &str) -> Option<(u8, u8)> {
+    parse_channel_value(value).filter(|&(_, x): &(u8, u8)| x < 128)
+}
+
+fn channel_priority(value: String) -> Result<(), String> {
+    parse_channel_priority(&value)
+        .map(|_| ())
+        .ok_or_else(|| format!("'{}' is not a channel priority (e.g. 10=100)", value))
+}
+
+fn channel_reserve(value: String) -> Result<(), String> {
+    parse_channel_value::<usize>(&value)
+        .map(|_| ())
+        .ok_or_else(|| format!("'{}' is not a channel reservation (e.g. 10=8)", value))
+}
+
+fn channel_gain(value: String) -> Result<(), String> {
+    parse_channel_gain(&value)
+        .map(|_| ())
+        .ok_or_else(|| format!("'{}' is not a channel gain (e.g. 10=-3)", value))
+}
+
+fn positive(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(x) if x > 0.0 && x.is_finite() => Ok(()),
+        _ => Err(format!("'{}' is not a positive number", value)),
+    }
+}
+
+fn time(value: String) -> Result<(), String> {
+    parse_time(&value)
+        .map(|_| ())
+        .ok_or_else(|| format!("'{}' is not a time (seconds or m:ss)", value))
+}
+
+#[inline]
+fn db_to_gain(db: f64) -> f64 {
+    10.0f64.powf(db / 20.0)
+}
+
+/// Options shared by the `play` and `render` subcommands.
+pub struct PlaybackOptions {
+    pub sample_rate: Option<u32>,
+    pub preset: Option<u8>,
+    pub random_presets: bool,
+    pub transpose: i8,
+    pub tempo: f64,
+    /// Start position in microseconds of song time.
+    pub start: f64,
+    /// Only pattern of a format 2 file which is played.
+    pub pattern: Option<usize>,
+    /// Master gain in decibels.
+    pub gain: Option<f64>,
+    /// Transpositions of midi channels `(index, semitones)`.
+    pub channel_transpose: Vec<(u8, i8)>,
+    /// Indices of muted and soloed midi channels.
+    pub muted: Vec<u8>,
+    pub soloed: Vec<u8>,
+    pub muted_tracks: Vec<usize>,
+    pub soloed_tracks: Vec<usize>,
+    /// Gains of midi channels `(index, decibels)`.
+    pub channel_gains: Vec<(u8, f64)>,
+    /// Voices of each melodic channel, used when creating `MidiPlayback`.
+    pub polyphony: usize,
+    pub steal_policy: StealPolicy,
+    /// Voices playing at once across all channels.
+    pub voices: usize,
+    /// Priorities of midi channels `(index, priority)`.
+    pub priorities: Vec<(u8, u8)>,
+    /// Reserved voices of midi channels `(index, voices)`.
+    pub reserved: Vec<(u8, usize)>,
+    pub limiter: bool,
+}
+
+impl PlaybackOptions {
+    /// Reads options from matches of a subcommand, values were already validated.
+    pub fn new(matches: &ArgMatches) -> Self {
+        PlaybackOptions {
+            sample_rate: value_t!(matches, "sample-rate", u32).ok(),
+            preset: value_t!(matches, "preset", u8).ok(),
+            random_presets: matches.is_present("random-presets"),
+            transpose: value_t!(matches, "transpose", i8).unwrap_or(0),
+            tempo: value_t!(matches, "tempo", f64).unwrap_or(1.0),
+            start: matches.value_of("start").and_then(parse_time).unwrap_or(0.0),
+            pattern: value_t!(matches, "pattern", usize).ok(),
+            channel_transpose: matches.values_of("channel-transpose")
+                .map(|values| values.filter_map(parse_channel_transpose).collect())
+                .unwrap_or_default(),
+            muted: matches.values_of("mute")
+                .map(|values| values.filter_map(parse_channel).collect())
+                .unwrap_or_default(),
+            soloed: matches.values_of("solo")
+                .map(|values| values.filter_map(parse_channel).collect())
+                .unwrap_or_default(),
+            muted_tracks: values_t!(matches, "mute-track", usize).unwrap_or_default(),
+            soloed_tracks: values_t!(matches, "solo-track", usize).unwrap_or_default(),
+            gain: value_t!(matches, "gain", f64).ok(),
+            channel_gains: matches.values_of("channel-gain")
+                .map(|values| values.filter_map(parse_channel_gain).collect())
+                .unwrap_or_default(),
+            polyphony: value_t!(matches, "polyphony", usize).unwrap_or(DEFAULT_POLYPHONY),
+            steal_policy: match matches.value_of("steal-policy") {
+                Some("quietest") => StealPolicy::Quietest,
+                Some("lowest-velocity") => StealPolicy::LowestVelocity,
+                Some("same-note") => StealPolicy::SameNote,
+                _ => StealPolicy::Oldest,
+            },
+            voices: value_t!(matches, "voices", usize).unwrap_or(DEFAULT_VOICE_LIMIT),
+            priorities: matches.values_of("priority")
+                .map(|values| values.filter_map(parse_channel_priority).collect())
+                .unwrap_or_default(),
+            reserved: matches.values_of("reserve")
+                .map(|values| values.filter_map(parse_channel_value).collect())
+                .unwrap_or_default(),
+            limiter: !matches.is_present("no-limiter"),
+        }
+    }
+
+    /// Creates player of the whole song or of the selected pattern.
+    pub fn player<'a>(&self, midi: &'a Midi) -> Result<Player<'a>, String> {
+        match self.pattern {
+            None => Ok(Player::new(midi)),
+            Some(_) if midi.patterns() == 0 => Err("--pattern needs a format 2 file".to_string()),
+            Some(index) => Player::pattern(midi, index)
+                .ok_or_else(|| format!("no pattern {}, the file has {} patterns", index, midi.patterns())),
+        }
+    }
+
+    pub fn apply(&self, player: &mut Player, playback: &mut MidiPlayback) -> Result<(), String> {
+        player.set_speed(self.tempo);
+        playback.set_steal_policy(self.steal_policy);
+        playback.set_voice_limit(self.voices);
+        for &(ch, priority) in self.priorities.iter() {
+            playback.set_priority(ch, priority);
+        }
+        for &(ch, voices) in self.reserved.iter() {
+            playback.reserve_voices(ch, voices);
+        }
+        playback.set_transpose(self.transpose);
+        for &(ch, semitones) in self.channel_transpose.iter() {
+            playback.set_channel_transpose(ch, semitones);
+        }
+
+        if let Some(program) = self.preset {
+            let preset = *playback.bank().get(Bank::CAPITAL, GMInstrument::new(program).unwrap());
+            playback.fixed_preset(&preset);
+        }
+
+        if self.random_presets {
+            playback.random_presets();
+        }
+
+        for &ch in self.muted.iter() {
+            playback.set_mute(ch, true);
+        }
+        for &ch in self.soloed.iter() {
+            playback.set_solo(ch, true);
+        }
+
+        for &track in self.muted_tracks.iter().chain(self.soloed_tracks.iter()) {
+            if track >= player.tracks() {
+                return Err(format!("no track {}, the file has {} tracks", track, player.tracks()));
+            }
+        }
+        for &track in self.muted_tracks.iter() {
+            player.set_mute(track, true);
+        }
+        for &track in self.soloed_tracks.iter() {
+            player.set_solo(track, true);
+        }
+
+        for &(ch, gain) in self.channel_gains.iter() {
+            playback.set_channel_gain(ch, db_to_gain(gain));
+        }
+
+        if let Some(gain) = self.gain {
+            playback.master_mut().set_gain(db_to_gain(gain));
+        }
+        if !self.limiter {
+            playback.master_mut().disable_limiter();
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+use std::io::BufRead;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use crate::midi::Channel;
+
+/// Part of the song which is muted or soloed.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Target {
+    /// Midi channel index (0 - 15).
+    Channel(Channel),
+    /// Track index in the file.
+    Track(usize),
+}
+
+/// Command entered by the user while the song is playing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Pauses or resumes playback.
+    TogglePause,
+    /// Jumps to song time in microseconds.
+    Seek(f64),
+    /// Jumps to marker with specified name.
+    Marker(String),
+    /// Plays section between two song times repeatedly, `None` stops looping.
+    Loop(Option<(f64, f64)>),
+    /// Sets playback speed, 1.0 is the original tempo.
+    Tempo(f64),
+    /// Transposes melodic channels by semitones.
+    Transpose(i8),
+    /// Mutes or unmutes channel or track.
+    ToggleMute(Target),
+    /// Solos or unsolos channel or track.
+    ToggleSolo(Target),
+    Quit,
+}
+
+pub const HELP: &str = "commands:
+  p                pause / resume
+  s <TIME>         seek to time (seconds or m:ss)
+  m <MARKER>       jump to marker by its name
+  l <A> <B>        loop section between two times
+  l off            stop looping
+  tempo <SCALE>    play faster (> 1.0) or slower (< 1.0)
+  t <SEMITONES>    transpose melodic channels
+  mute <CHANNEL>   mute / unmute midi channel (1-16)
+  solo <CHANNEL>   solo / unsolo midi channel (1-16)
+  mute track <N>   mute / unmute track
+  solo track <N>   solo / unsolo track
+  q                quit";
+
+/// Parses time given as seconds (`90.5`) or minutes and seconds (`1:30.5`)
+/// into microseconds.
+pub fn parse_time(value: &str) -> Option<f64> {
+    let mut parts = value.rsplitn(2, ':');
+    let seconds = parts.next()?.parse::<f64>().ok()?;
+    let minutes = match parts.next() {
+        Some(minutes) => minutes.parse::<u32>().ok()?,
+        None => 0,
+    };
+
+    if seconds < 0.0 || !seconds.is_finite() {
+        return None;
+    }
+    Some((minutes as f64 * 60.0 + seconds) * 1_000_000.0)
+}
+
+fn parse_target(words: &[&str]) -> Result<Target, String> {
+    match words {
+        ["track", track] => track.parse::<usize>()
+            .map(Target::Track)
+            .map_err(|_| format!("'{}' is not a track number", track)),
+        [channel] => match channel.parse::<u8>() {
+            Ok(x) if (1..=16).contains(&x) => Ok(Target::Channel(x - 1)),
+            _ => Err(format!("'{}' is not a midi channel (1-16)", channel)),
+        },
+        _ => Err("expected midi channel or track <N>".to_string()),
+    }
+}
+
+/// Parses one line of user input.
+pub fn parse(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let time = |value: &str| parse_time(value).ok_or_else(|| format!("'{}' is not a time", value));
+
+    match words.as_slice() {
+        ["p"] | ["pause"] => Ok(Command::TogglePause),
+        ["s", t] | ["seek", t] => Ok(Command::Seek(time(t)?)),
+        ["m", _, ..] | ["marker", _, ..] => Ok(Command::Marker(words[1..].join(" "))),
+        ["l", "off"] | ["loop", "off"] => Ok(Command::Loop(None)),
+        ["l", a, b] | ["loop", a, b] => {
+            let (a, b) = (time(a)?, time(b)?);
+            if a >= b {
+                return Err("loop end has to be after its start".to_string());
+            }
+            Ok(Command::Loop(Some((a, b))))
+        }
+        ["tempo", scale] => match scale.parse::<f64>() {
+            Ok(x) if x > 0.0 && x.is_finite() => Ok(Command::Tempo(x)),
+            _ => Err(format!("'{}' is not a positive number", scale)),
+        },
+        ["t", semitones] | ["transpose", semitones] => match semitones.parse::<i8>() {
+            Ok(x) if (-48..=48).contains(&x) => Ok(Command::Transpose(x)),
+            _ => Err(format!("'{}' is not a transposition (-48 to 48 semitones)", semitones)),
+        },
+        ["mute", target @ ..] => Ok(Command::ToggleMute(parse_target(target)?)),
+        ["solo", target @ ..] => Ok(Command::ToggleSolo(parse_target(target)?)),
+        ["q"] | ["quit"] => Ok(Command::Quit),
+        _ => Err(format!("unknown command '{}'\n{}", line.trim(), HELP)),
+    }
+}
+
+/// Reads commands from the standard input on a separate thread, so that
+/// the audio callback only has to poll the returned receiver.
+pub fn spawn() -> Receiver<Command> {
+    let (sender, receiver) = channel();
+
+    thread::spawn(move || {
+        let stdin = std::io::stdin();
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match parse(&line) {
+                Ok(command) => if sender.send(command).is_err() {
+                    break;
+                },
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+    });
+
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::control::{parse, parse_time, Command, Target};
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse_time("1:30.5"), Some(90_500_000.0));
+        assert_eq!(parse_time("2"), Some(2_000_000.0));
+        assert_eq!(parse_time("-1"), None);
+
+        assert_eq!(parse("p"), Ok(Command::TogglePause));
+        assert_eq!(parse("seek 0:10"), Ok(Command::Seek(10_000_000.0)));
+        assert_eq!(parse("m second verse"), Ok(Command::Marker("second verse".to_string())));
+        assert_eq!(parse("l 1 2"), Ok(Command::Loop(Some((1_000_000.0, 2_000_000.0)))));
+        assert_eq!(parse("loop off"), Ok(Command::Loop(None)));
+        assert_eq!(parse("tempo 0.5"), Ok(Command::Tempo(0.5)));
+        assert_eq!(parse("t -3"), Ok(Command::Transpose(-3)));
+        assert!(parse("t 60").is_err());
+        assert_eq!(parse("mute 10"), Ok(Command::ToggleMute(Target::Channel(9))));
+        assert_eq!(parse("solo track 2"), Ok(Command::ToggleSolo(Target::Track(2))));
+        assert!(parse("mute 17").is_err());
+        assert!(parse("l 2 1").is_err());
+        assert!(parse("jump").is_err());
+    }
+}
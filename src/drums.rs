@@ -0,0 +1,391 @@
+use crate::env::Envelope;
+use crate::env::EnvelopeState::{Attack, Release, Off, Sustain};
+use crate::filter::{Filter, Mode};
+use crate::osc::{Osc, Shape};
+use crate::math::pan_gains;
+use std::cmp::Ordering;
+
+/// Release time of a voice cut by another voice of the same choke group.
+const CHOKE_TIME: f64 = 0.01;
+
+/// Recipe of a synthesized percussion sound: a tonal body with pitch
+/// sweep and a filtered noise burst, both with their own decay.
+#[derive(Copy, Clone)]
+pub struct DrumSound {
+    pub tone_shape: Shape,
+    pub tone_freq: f64,
+    /// Frequency added at the start of the sound, decays with `sweep_decay`.
+    pub tone_sweep: f64,
+    pub sweep_decay: f64,
+    pub tone_decay: f64,
+    pub tone_level: f64,
+    pub noise_level: f64,
+    pub noise_decay: f64,
+    pub noise_mode: Mode,
+    pub noise_cutoff: f64,
+    /// Sounds in the same group cut each other (e.g. open and closed hi-hat).
+    pub choke_group: Option<u8>,
+    /// Position in the kit (-1.0 left, 1.0 right).
+    pub pan: f64,
+}
+
+impl DrumSound {
+    fn tone(shape: Shape, freq: f64, decay: f64) -> Self {
+        DrumSound {
+            tone_shape: shape,
+            tone_freq: freq,
+            tone_sweep: 0.0,
+            sweep_decay: 0.01,
+            tone_decay: decay,
+            tone_level: 1.0,
+            noise_level: 0.0,
+            noise_decay: 0.01,
+            noise_mode: Mode::Highpass,
+            noise_cutoff: 0.5,
+            choke_group: None,
+            pan: 0.0,
+        }
+    }
+
+    fn noise(mode: Mode, cutoff: f64, decay: f64) -> Self {
+        DrumSound {
+            tone_level: 0.0,
+            noise_level: 1.0,
+            noise_decay: decay,
+            noise_mode: mode,
+            noise_cutoff: cutoff,
+            ..DrumSound::tone(Shape::Sine, 0.0, 0.01)
+        }
+    }
+
+    fn kick(freq: f64) -> Self {
+        DrumSound {
+            tone_sweep: freq * 3.0,
+            sweep_decay: 0.04,
+            noise_level: 0.15,
+            noise_decay: 0.01,
+            ..DrumSound::tone(Shape::Sine, freq, 0.35)
+        }
+    }
+
+    fn snare(freq: f64, decay: f64) -> Self {
+        DrumSound {
+            tone_freq: freq,
+            tone_level: 0.5,
+            tone_sweep: freq * 0.5,
+            sweep_decay: 0.02,
+            tone_decay: 0.1,
+            ..DrumSound::noise(Mode::Bandpass, 0.4, decay)
+        }
+    }
+
+    fn tom(freq: f64) -> Self {
+        DrumSound {
+            tone_sweep: freq * 0.6,
+            sweep_decay: 0.08,
+            noise_level: 0.1,
+            noise_decay: 0.02,
+            ..DrumSound::tone(Shape::Sine, freq, 0.4)
+        }
+    }
+
+    fn choke(mut self, group: u8) -> Self {
+        self.choke_group = Some(group);
+        self
+    }
+
+    /// Returns the sound of a General MIDI percussion key (35 - 81).
+    pub fn gm(key: u8) -> Option<Self> {
+        const HI_HAT: u8 = 1;
+        const WHISTLE: u8 = 2;
+        const GUIRO: u8 = 3;
+        const CUICA: u8 = 4;
+        const TRIANGLE: u8 = 5;
+
+        let sound = match key {
+            35 => DrumSound::kick(45.0),                                   /* Acoustic Bass Drum */
+            36 => DrumSound::kick(55.0),                                   /* Bass Drum 1 */
+            37 => DrumSound::tone(Shape::Triangle, 1800.0, 0.03),          /* Side Stick */
+            38 => DrumSound::snare(190.0, 0.18),                           /* Acoustic Snare */
+            39 => DrumSound::noise(Mode::Bandpass, 0.3, 0.12),             /* Hand Clap */
+            40 => DrumSound::snare(220.0, 0.14),                           /* Electric Snare */
+            41 => DrumSound::tom(70.0),                                    /* Low Floor Tom */
+            42 => DrumSound::noise(Mode::Highpass, 0.7, 0.05).choke(HI_HAT), /* Closed Hi-Hat */
+            43 => DrumSound::tom(85.0),                                    /* High Floor Tom */
+            44 => DrumSound::noise(Mode::Highpass, 0.7, 0.08).choke(HI_HAT), /* Pedal Hi-Hat */
+            45 => DrumSound::tom(100.0),                                   /* Low Tom */
+            46 => DrumSound::noise(Mode::Highpass, 0.65, 0.5).choke(HI_HAT), /* Open Hi-Hat */
+            47 => DrumSound::tom(120.0),                                   /* Low-Mid Tom */
+            48 => DrumSound::tom(140.0),                                   /* Hi-Mid Tom */
+            49 | 57 => DrumSound::noise(Mode::Highpass, 0.5, 1.5),         /* Crash Cymbal 1, 2 */
+            50 => DrumSound::tom(165.0),                                   /* High Tom */
+            51 | 59 => DrumSound::noise(Mode::Bandpass, 0.8, 1.0),         /* Ride Cymbal 1, 2 */
+            52 => DrumSound::noise(Mode::Bandpass, 0.6, 1.2),              /* Chinese Cymbal */
+            53 => DrumSound::tone(Shape::Triangle, 2400.0, 0.6),           /* Ride Bell */
+            54 => DrumSound::noise(Mode::Highpass, 0.8, 0.15),             /* Tambourine */
+            55 => DrumSound::noise(Mode::Highpass, 0.6, 0.6),              /* Splash Cymbal */
+            56 => DrumSound::tone(Shape::Square, 560.0, 0.15),             /* Cowbell */
+            58 => DrumSound::noise(Mode::Bandpass, 0.2, 0.8),              /* Vibraslap */
+            60 => DrumSound::tom(400.0),                                   /* Hi Bongo */
+            61 => DrumSound::tom(300.0),                                   /* Low Bongo */
+            62 => DrumSound::tone(Shape::Sine, 330.0, 0.05),               /* Mute Hi Conga */
+            63 => DrumSound::tom(330.0),                                   /* Open Hi Conga */
+            64 => DrumSound::tom(220.0),                                   /* Low Conga */
+            65 => DrumSound::snare(450.0, 0.15),                           /* High Timbale */
+            66 => DrumSound::snare(320.0, 0.18),                           /* Low Timbale */
+            67 => DrumSound::tone(Shape::Triangle, 900.0, 0.2),            /* High Agogo */
+            68 => DrumSound::tone(Shape::Triangle, 600.0, 0.2),            /* Low Agogo */
+            69 => DrumSound::noise(Mode::Highpass, 0.75, 0.08),            /* Cabasa */
+            70 => DrumSound::noise(Mode::Highpass, 0.85, 0.06),            /* Maracas */
+            71 => DrumSound::tone(Shape::Sine, 2500.0, 0.1).choke(WHISTLE), /* Short Whistle */
+            72 => DrumSound::tone(Shape::Sine, 2200.0, 0.5).choke(WHISTLE), /* Long Whistle */
+            73 => DrumSound::noise(Mode::Bandpass, 0.35, 0.08).choke(GUIRO), /* Short Guiro */
+            74 => DrumSound::noise(Mode::Bandpass, 0.35, 0.35).choke(GUIRO), /* Long Guiro */
+            75 => DrumSound::tone(Shape::Sine, 2500.0, 0.04),              /* Claves */
+            76 => DrumSound::tone(Shape::Triangle, 800.0, 0.06),           /* Hi Wood Block */
+            77 => DrumSound::tone(Shape::Triangle, 550.0, 0.07),           /* Low Wood Block */
+            78 => DrumSound::tom(600.0).choke(CUICA),                      /* Mute Cuica */
+            79 => DrumSound::tom(450.0).choke(CUICA),                      /* Open Cuica */
+            80 => DrumSound::tone(Shape::Triangle, 4000.0, 0.08).choke(TRIANGLE), /* Mute Triangle */
+            81 => DrumSound::tone(Shape::Triangle, 4000.0, 1.2).choke(TRIANGLE),  /* Open Triangle */
+            _ => return None,
+        };
+
+        Some(DrumSound { pan: gm_pan(key), ..sound })
+    }
+}
+
+/// Position of the General MIDI percussion key in the kit as seen from the
+/// audience: hi-hat on the left, toms from high (left) to floor (right),
+/// latin percussion spread by pitch.
+fn gm_pan(key: u8) -> f64 {
+    match key {
+        42 | 44 | 46 => -0.3,
+        50 | 48 => -0.2,
+        47 | 45 => 0.1,
+        43 | 41 => 0.3,
+        49 | 52 | 55 => -0.4,
+        51 | 53 | 57 | 59 => 0.4,
+        60..=81 => (key as f64 - 70.5) / 10.5 * 0.5,
+        _ => 0.0,
+    }
+}
+
+#[derive(Copy, Clone)]
+struct DrumVoice {
+    tone: Osc,
+    noise: Osc,
+    filter: Filter,
+    tone_env: Envelope,
+    noise_env: Envelope,
+    sweep_env: Envelope,
+    sound: DrumSound,
+    key: u8,
+    velocity: f64,
+    is_active: bool,
+    /// Output gains and the pan position they were computed for.
+    pan_gains: (f64, f64),
+    gains_pan: f64,
+}
+
+impl DrumVoice {
+    fn new(sample_rate: f64) -> Self {
+        let mut noise = Osc::new(sample_rate);
+        noise.shape = Shape::Noise;
+
+        DrumVoice {
+            tone: Osc::new(sample_rate),
+            noise,
+            filter: Filter::new(0.5),
+            tone_env: Envelope::new(sample_rate),
+            noise_env: Envelope::new(sample_rate),
+            sweep_env: Envelope::new(sample_rate),
+            sound: DrumSound::tone(Shape::Sine, 0.0, 0.01),
+            key: 0,
+            velocity: 0.0,
+            is_active: false,
+            pan_gains: (1.0, 1.0),
+            gains_pan: 0.0,
+        }
+    }
+
+    fn trigger(&mut self, key: u8, velocity: u8, sound: DrumSound) {
+        self.sound = sound;
+        self.key = key;
+        self.velocity = velocity as f64 / 127.0;
+        self.is_active = true;
+
+        self.tone.shape = sound.tone_shape;
+        self.tone.reset();
+        self.filter.reset();
+        self.filter.mode = sound.noise_mode;
+        self.filter.cutoff(sound.noise_cutoff);
+
+        /* one-shot envelopes: instant attack, decay to silence */
+        for (env, decay) in [(&mut self.tone_env, sound.tone_decay),
+                             (&mut self.noise_env, sound.noise_decay),
+                             (&mut self.sweep_env, sound.sweep_decay)].iter_mut() {
+            env.reset();
+            env.attack(0.001);
+            env.decay(*decay);
+            env.sustain(0.0);
+            env.release(CHOKE_TIME);
+            env.enter_state(Attack);
+        }
+    }
+
+    /// Current level of the louder of the two envelopes.
+    fn level(&self) -> f64 {
+        self.tone_env.level().max(self.noise_env.level()) * self.velocity
+    }
+
+    fn choke(&mut self) {
+        self.tone_env.enter_state(Release);
+        self.noise_env.enter_state(Release);
+    }
+
+    /// Returns next stereo sample `(left, right)`, `pan` is position of the kit.
+    fn next(&mut self, pan: f64) -> (f64, f64) {
+        let tone_state = self.tone_env.state();
+        let noise_state = self.noise_env.state();
+        if (tone_state == Sustain || tone_state == Off) && (noise_state == Sustain || noise_state == Off) {
+            self.is_active = false;
+            return (0.0, 0.0);
+        }
+
+        let pan = (pan + self.sound.pan).clamp(-1.0, 1.0);
+        if pan != self.gains_pan {
+            self.pan_gains = pan_gains(pan);
+            self.gains_pan = pan;
+        }
+
+        self.tone.frequency(self.sound.tone_freq + self.sound.tone_sweep * self.sweep_env.next());
+
+        let tone = self.tone.next() * self.tone_env.next() * self.sound.tone_level;
+        let noise = self.filter.next(self.noise.next()) * self.noise_env.next() * self.sound.noise_level;
+
+        let v = (tone + noise) * self.velocity;
+        (v * self.pan_gains.0, v * self.pan_gains.1)
+    }
+}
+
+/// Percussion engine of the General MIDI channel 10.
+pub struct DrumKit {
+    voices: Vec<DrumVoice>,
+    /// Stolen voices finishing their choke release.
+    fading: Vec<DrumVoice>,
+    /// Position of the whole kit (-1.0 - 1.0).
+    pan: f64,
+}
+
+impl DrumKit {
+    pub fn new(sample_rate: f64, polyphony: usize) -> Self {
+        DrumKit {
+            voices: vec![DrumVoice::new(sample_rate); polyphony],
+            fading: vec![],
+            pan: 0.0,
+        }
+    }
+
+    pub fn note_on(&mut self, key: u8, velocity: u8) {
+        let sound = match DrumSound::gm(key) {
+            Some(sound) => sound,
+            None => return,
+        };
+
+        if let Some(group) = sound.choke_group {
+            for v in self.voices.iter_mut() {
+                if v.is_active && v.sound.choke_group == Some(group) {
+                    v.choke();
+                }
+            }
+        }
+
+        /* retrigger the same key or take a free voice */
+        let idx = self.voices.iter().position(|v| v.is_active && v.key == key)
+            .or_else(|| self.voices.iter().position(|v| !v.is_active));
+
+        if let Some(idx) = idx {
+            self.voices[idx].trigger(key, velocity, sound);
+        }
+    }
+
+    /// Chokes the quietest voice and frees it for another sound. Returns
+    /// false when no voice is playing.
+    pub fn steal_voice(&mut self) -> bool {
+        let idx = self.voices.iter()
+            .enumerate()
+            .filter(|(_, v)| v.is_active)
+            .min_by(|(_, a), (_, b)| a.level().partial_cmp(&b.level()).unwrap_or(Ordering::Equal))
+            .map(|(idx, _)| idx);
+
+        match idx {
+            Some(idx) => {
+                let mut voice = self.voices[idx];
+                voice.choke();
+                self.fading.push(voice);
+                self.voices[idx].is_active = false;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Silences all voices immediately.
+    pub fn all_sound_off(&mut self) {
+        for v in self.voices.iter_mut() {
+            v.is_active = false;
+        }
+        self.fading.clear();
+    }
+
+    pub fn voices(&self) -> (usize, usize) {
+        (self.voices.len(), self.voices.iter().filter(|x| x.is_active).count())
+    }
+
+    /// Sets position of the whole kit (-1.0 hard left, 1.0 hard right).
+    pub fn pan(&mut self, pan: f64) {
+        self.pan = pan;
+    }
+
+    /// Returns next stereo sample `(left, right)`.
+    pub fn next(&mut self) -> (f64, f64) {
+        let pan = self.pan;
+        let (mut l, mut r) = (0.0, 0.0);
+
+        for v in self.fading.iter_mut().chain(self.voices.iter_mut().filter(|v| v.is_active)) {
+            let (x, y) = v.next(pan);
+            l += x;
+            r += y;
+        }
+        self.fading.retain(|v| v.is_active);
+
+        (l, r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::drums::DrumKit;
+
+    #[test]
+    fn one_shot_and_choke() {
+        let mut kit = DrumKit::new(44100.0, 4);
+
+        kit.note_on(46, 100); /* open hi-hat */
+        kit.note_on(36, 100); /* kick */
+        assert_eq!(kit.voices(), (4, 2));
+
+        kit.note_on(42, 100); /* closed hi-hat chokes the open one */
+        for _ in 0..2000 {
+            let (l, r) = kit.next();
+            assert!(l.is_finite() && r.is_finite());
+        }
+        assert_eq!(kit.voices(), (4, 2));
+
+        for _ in 0..44100 {
+            kit.next();
+        }
+        assert_eq!(kit.voices(), (4, 0));
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::VecDeque;
+
+/// Time the limiter looks ahead to reduce gain before a peak arrives.
+const LOOKAHEAD_TIME: f64 = 0.005;
+/// Time constant of the gain recovery after a peak.
+const RELEASE_TIME: f64 = 0.1;
+
+/// Look-ahead brickwall limiter. The signal is delayed by the look-ahead
+/// time while the gain ramps down, so peaks never exceed the threshold.
+pub struct Limiter {
+    threshold: f64,
+    /// Delayed input frames.
+    delay: VecDeque<(f64, f64)>,
+    /// Window minimum of required gains, as `(frame, gain)` increasing in gain.
+    minimum: VecDeque<(u64, f64)>,
+    /// Last window minimums, averaged into a smooth gain ramp.
+    ramp: VecDeque<f64>,
+    ramp_sum: f64,
+    window: usize,
+    release: f64,
+    gain: f64,
+    frame: u64,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: f64, threshold: f64) -> Self {
+        let window = ((LOOKAHEAD_TIME * sample_rate) as usize).max(1);
+
+        Limiter {
+            threshold,
+            delay: VecDeque::with_capacity(window),
+            minimum: VecDeque::with_capacity(window),
+            ramp: VecDeque::with_capacity(window),
+            ramp_sum: 0.0,
+            window,
+            release: 1.0 - (-1.0 / (RELEASE_TIME * sample_rate)).exp(),
+            gain: 1.0,
+            frame: 0,
+        }
+    }
+
+    /// Latency of the limiter in frames.
+    #[inline]
+    pub fn latency(&self) -> usize {
+        self.window - 1
+    }
+
+    pub fn next(&mut self, (l, r): (f64, f64)) -> (f64, f64) {
+        let peak = l.abs().max(r.abs());
+        let required = if peak > self.threshold { self.threshold / peak } else { 1.0 };
+
+        /* minimum of the required gain over the last window */
+        while let Some(&(_, gain)) = self.minimum.back() {
+            if gain < required {
+                break;
+            }
+            self.minimum.pop_back();
+        }
+        self.minimum.push_back((self.frame, required));
+        while let Some(&(frame, _)) = self.minimum.front() {
+            if frame + self.window as u64 > self.frame {
+                break;
+            }
+            self.minimum.pop_front();
+        }
+        let minimum = self.minimum.front().unwrap().1;
+
+        /* moving average of the minimum reaches the required gain
+           exactly when the peak leaves the delay line */
+        self.ramp.push_back(minimum);
+        self.ramp_sum += minimum;
+        if self.ramp.len() > self.window {
+            self.ramp_sum -= self.ramp.pop_front().unwrap();
+        }
+        let target = (self.ramp_sum / self.window as f64 + (self.window - self.ramp.len()) as f64 / self.window as f64).min(1.0);
+
+        self.gain = if target < self.gain {
+            target
+        } else {
+            self.gain + (target - self.gain) * self.release
+        };
+
+        self.delay.push_back((l, r));
+        self.frame += 1;
+        if self.delay.len() < self.window {
+            return (0.0, 0.0);
+        }
+
+        let (l, r) = self.delay.pop_front().unwrap();
+        let t = self.threshold;
+        ((l * self.gain).clamp(-t, t), (r * self.gain).clamp(-t, t))
+    }
+}
+
+/// Statistics of the master bus output.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct MasterStats {
+    /// Frames which exceeded full scale before the limiter.
+    pub clipped: u64,
+    /// Highest absolute sample value after the limiter.
+    pub peak: f64,
+}
+
+/// Final stage of the mix: master gain followed by the limiter.
+pub struct MasterBus {
+    gain: f64,
+    limiter: Option<Limiter>,
+    stats: MasterStats,
+}
+
+impl MasterBus {
+    pub fn new(sample_rate: f64) -> Self {
+        MasterBus {
+            gain: 0.5,
+            limiter: Some(Limiter::new(sample_rate, 1.0)),
+            stats: MasterStats::default(),
+        }
+    }
+
+    pub fn set_gain(&mut self, gain: f64) {
+        self.gain = gain;
+    }
+
+    /// Removes the limiter, samples over full scale are left for the output to clip.
+    pub fn disable_limiter(&mut self) {
+        self.limiter = None;
+    }
+
+    #[inline]
+    pub fn stats(&self) -> MasterStats {
+        self.stats
+    }
+
+    /// Frames the output is delayed by the limiter.
+    #[inline]
+    pub fn latency(&self) -> usize {
+        self.limiter.as_ref().map(|x| x.latency()).unwrap_or(0)
+    }
+
+    pub fn next(&mut self, (l, r): (f64, f64)) -> (f64, f64) {
+        let (l, r) = (l * self.gain, r * self.gain);
+        if l.abs() > 1.0 || r.abs() > 1.0 {
+            self.stats.clipped += 1;
+        }
+
+        let (l, r) = match self.limiter {
+            Some(ref mut limiter) => limiter.next((l, r)),
+            None => (l, r),
+        };
+        self.stats.peak = self.stats.peak.max(l.abs()).max(r.abs());
+
+        (l, r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::effects::{Limiter, MasterBus};
+
+    #[test]
+    fn brickwall() {
+        let mut limiter = Limiter::new(1000.0, 1.0);
+        assert_eq!(limiter.latency(), 4);
+
+        let input: Vec<f64> = (0..100).map(|i| if i == 50 { 4.0 } else { 0.5 }).collect();
+        let output: Vec<(f64, f64)> = input.iter().map(|&x| limiter.next((x, -x))).collect();
+
+        /* delayed by the latency, peak is limited and gain ramps down before it */
+        assert_eq!(output[3], (0.0, 0.0));
+        assert_eq!(output[10], (0.5, -0.5));
+        assert_eq!(output[54], (1.0, -1.0));
+        assert!(output[52].0 < 0.5 && output[52].0 > 0.125);
+        assert!(output.iter().all(|&(l, r)| l.abs() <= 1.0 && r.abs() <= 1.0));
+
+        /* gain recovers after the peak */
+        assert!(output[60].0 < 0.5 && output[99].0 > output[60].0);
+
+        let mut master = MasterBus::new(1000.0);
+        assert_eq!(master.latency(), 4);
+        master.disable_limiter();
+        assert_eq!(master.latency(), 0);
+    }
+}
@@ -66,6 +66,11 @@ impl Envelope {
         self.state
     }
 
+    #[inline]
+    pub fn level(&self) -> f64 {
+        self.current_level
+    }
+
     #[inline]
     pub fn attack(&mut self, attack: f64) {
         self.attack = attack;
@@ -0,0 +1,248 @@
+use std::slice::SliceIndex;
+use crate::midi::Midi;
+use crate::meta::{Text, TextKind, Timed};
+
+/// Highlight of the syllables which were already sung.
+const SUNG_STYLE: &str = "\x1b[1;33m";
+const RESET_STYLE: &str = "\x1b[0m";
+/// Clears the rest of the terminal line.
+const CLEAR_LINE: &str = "\x1b[K";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Syllable {
+    /// Song time in microseconds.
+    pub time: f64,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub syllables: Vec<Syllable>,
+    /// Line starts a new paragraph (verse).
+    pub paragraph: bool,
+}
+
+impl Line {
+    /// Joins text of the syllables in specified range, `..` for the whole line.
+    pub fn text<R: SliceIndex<[Syllable], Output=[Syllable]>>(&self, syllables: R) -> String {
+        self.syllables[syllables].iter().map(|x| x.text.as_str()).collect()
+    }
+}
+
+/// Lyrics of a song split into lines of timed syllables. Karaoke (.kar)
+/// files keep them in text events, where `@` starts a header (`@T` title,
+/// `@I` information, ...), `/` starts a new line and `\` a new paragraph.
+/// Headers may be spread over several tracks, the syllables are in the
+/// track with the most text events. Other files use lyric events, which
+/// may end lines with carriage return or line feed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lyrics {
+    /// Headers of a karaoke file without the `@`, e.g. `TSong title`.
+    pub headers: Vec<String>,
+    pub lines: Vec<Line>,
+}
+
+impl Lyrics {
+    /// Returns lyrics of the song, `None` if it has none.
+    pub fn from_midi(midi: &Midi) -> Option<Self> {
+        let mut lyrics = Lyrics { headers: vec![], lines: vec![] };
+        let karaoke = midi.texts(TextKind::Text).any(|x| x.value.text.starts_with("@K"));
+
+        let texts: Vec<&Timed<Text>> = if karaoke {
+            lyrics.headers = midi.texts(TextKind::Text)
+                .filter(|x| x.value.text.starts_with('@'))
+                .map(|x| x.value.text[1..].trim().to_string())
+                .collect();
+
+            /* soft karaoke keeps the syllables in a separate "Words" track */
+            let mut counts = vec![0; midi.texts.iter().map(|x| x.track + 1).max().unwrap_or(0)];
+            for x in midi.texts(TextKind::Text).filter(|x| !x.value.text.starts_with('@')) {
+                counts[x.track] += 1;
+            }
+            let words = (0..counts.len()).max_by_key(|&track| counts[track]).unwrap_or(0);
+
+            midi.texts(TextKind::Text)
+                .filter(|x| x.track == words && !x.value.text.starts_with('@'))
+                .collect()
+        } else {
+            midi.texts(TextKind::Lyric).collect()
+        };
+
+        let mut new_line = true;
+        let mut paragraph = false;
+
+        for x in texts {
+            let mut text = x.value.text.as_str();
+
+            if text.starts_with('\\') {
+                paragraph = true;
+                new_line = true;
+                text = &text[1..];
+            } else if text.starts_with('/') {
+                new_line = true;
+                text = &text[1..];
+            }
+
+            let ends_line = text.ends_with('\r') || text.ends_with('\n');
+            let text = text.trim_end_matches(&['\r', '\n'][..]);
+
+            if !text.is_empty() {
+                if new_line || lyrics.lines.is_empty() {
+                    lyrics.lines.push(Line { syllables: vec![], paragraph });
+                    new_line = false;
+                    paragraph = false;
+                }
+                lyrics.lines.last_mut().unwrap().syllables.push(Syllable { time: x.time, text: text.to_string() });
+            }
+
+            if ends_line {
+                new_line = true;
+            }
+        }
+
+        if lyrics.lines.is_empty() { None } else { Some(lyrics) }
+    }
+
+    /// Title lines from the `@T` headers of a karaoke file.
+    pub fn title(&self) -> impl Iterator<Item=&str> {
+        self.headers.iter()
+            .filter(|x| x.starts_with('T'))
+            .map(|x| x[1..].trim())
+    }
+
+    /// Returns `(line, syllable)` of the last syllable sung until specified song time.
+    pub fn position(&self, time_micros: f64) -> Option<(usize, usize)> {
+        let mut position = None;
+
+        for (l, line) in self.lines.iter().enumerate() {
+            for (s, syllable) in line.syllables.iter().enumerate() {
+                if syllable.time > time_micros {
+                    return position;
+                }
+                position = Some((l, s));
+            }
+        }
+
+        position
+    }
+}
+
+/// Prints lyrics in the terminal line by line and highlights syllables as
+/// they are sung.
+pub struct LyricsDisplay<'a> {
+    lyrics: &'a Lyrics,
+    position: Option<(usize, usize)>,
+}
+
+impl<'a> LyricsDisplay<'a> {
+    pub fn new(lyrics: &'a Lyrics) -> Self {
+        LyricsDisplay { lyrics, position: None }
+    }
+
+    /// Returns text to print when the song reached another syllable. Current
+    /// line is redrawn in place, a new line is started below the previous one.
+    pub fn update(&mut self, time_micros: f64) -> Option<String> {
+        let position = self.lyrics.position(time_micros);
+        if position == self.position {
+            return None;
+        }
+
+        let previous = self.position;
+        self.position = position;
+        let (l, s) = position?;
+        let line = &self.lyrics.lines[l];
+
+        let mut output = String::new();
+        if previous.map(|(previous, _)| previous) != Some(l) {
+            output.push('\n');
+            if line.paragraph {
+                output.push('\n');
+            }
+        }
+
+        output.push_str(&format!("\r{}{}{}{}{}", SUNG_STYLE, line.text(..=s), RESET_STYLE, line.text(s + 1..), CLEAR_LINE));
+
+        Some(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::karaoke::{Lyrics, LyricsDisplay, Line, Syllable};
+    use crate::meta::{Text, TextKind, Timed};
+    use crate::midi::Midi;
+
+    fn text(track: usize, time: f64, text: &str) -> Timed<Text> {
+        Timed { value: Text { kind: TextKind::Text, text: text.to_string() }, track, tick: 0, time }
+    }
+
+    #[test]
+    fn karaoke_lyrics() {
+        let mut midi = Midi::new("song.kar".to_string());
+        midi.texts = vec![
+            text(1, 0.0, "Copyright"),
+            text(2, 0.0, "@KMIDI KARAOKE FILE"),
+            text(2, 0.0, "@TSong"),
+            text(2, 1.0, "\\Twin"),
+            text(2, 2.0, "kle "),
+            text(2, 3.0, "/lit"),
+            text(2, 4.0, "tle"),
+        ];
+
+        let lyrics = Lyrics::from_midi(&midi).unwrap();
+        assert_eq!(lyrics.title().collect::<Vec<_>>(), vec!["Song"]);
+        assert_eq!(lyrics.lines.len(), 2);
+        assert_eq!(lyrics.lines[0].text(..), "Twinkle ");
+        assert!(lyrics.lines[0].paragraph);
+        assert_eq!(lyrics.lines[1], Line {
+            syllables: vec![Syllable { time: 3.0, text: "lit".to_string() }, Syllable { time: 4.0, text: "tle".to_string() }],
+            paragraph: false,
+        });
+
+        assert_eq!(lyrics.position(0.5), None);
+        assert_eq!(lyrics.position(3.5), Some((1, 0)));
+
+        let mut display = LyricsDisplay::new(&lyrics);
+        assert_eq!(display.update(0.5), None);
+        assert_eq!(display.update(1.0), Some("\n\n\r\x1b[1;33mTwin\x1b[0mkle \x1b[K".to_string()));
+        assert_eq!(display.update(1.5), None);
+        assert_eq!(display.update(2.0), Some("\r\x1b[1;33mTwinkle \x1b[0m\x1b[K".to_string()));
+        assert!(display.update(3.0).unwrap().starts_with("\n\r"));
+    }
+
+    #[test]
+    fn soft_karaoke_tracks() {
+        /* headers in one track, title and syllables in the "Words" track */
+        let mut midi = Midi::new("song.kar".to_string());
+        midi.texts = vec![
+            text(1, 0.0, "@KMIDI KARAOKE FILE"),
+            text(1, 0.0, "@V0100"),
+            text(1, 0.0, "@IFree"),
+            text(2, 0.0, "@LENGL"),
+            text(2, 0.0, "@TSong"),
+            text(3, 0.5, "Melody"),
+            text(2, 1.0, "\\Row "),
+            text(2, 2.0, "row"),
+            text(2, 3.0, "/your boat"),
+        ];
+
+        let lyrics = Lyrics::from_midi(&midi).unwrap();
+        assert_eq!(lyrics.headers, vec!["KMIDI KARAOKE FILE", "V0100", "IFree", "LENGL", "TSong"]);
+        assert_eq!(lyrics.title().collect::<Vec<_>>(), vec!["Song"]);
+        assert_eq!(lyrics.lines.iter().map(|x| x.text(..)).collect::<Vec<_>>(), vec!["Row row", "your boat"]);
+    }
+
+    #[test]
+    fn lyric_events() {
+        let mut midi = Midi::new("song.mid".to_string());
+        midi.texts = vec![
+            Timed { value: Text { kind: TextKind::Lyric, text: "Hel".to_string() }, track: 0, tick: 0, time: 1.0 },
+            Timed { value: Text { kind: TextKind::Lyric, text: "lo\r".to_string() }, track: 0, tick: 0, time: 2.0 },
+            Timed { value: Text { kind: TextKind::Lyric, text: "world".to_string() }, track: 0, tick: 0, time: 3.0 },
+        ];
+
+        let lyrics = Lyrics::from_midi(&midi).unwrap();
+        assert_eq!(lyrics.lines.iter().map(|x| x.text(..)).collect::<Vec<_>>(), vec!["Hello", "world"]);
+        assert!(Lyrics::from_midi(&Midi::new("empty.mid".to_string())).is_none());
+    }
+}
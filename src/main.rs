@@ -1,23 +1,24 @@
 #![feature(clamp)]
 
-use cpal::{EventLoop, default_output_device, StreamData, UnknownTypeOutputBuffer};
-use device_query::{Keycode, DeviceState, DeviceQuery};
-use crate::midi::{load_midi, Kind, note2freq, Player, Event, MidiPlayback};
-use std::time::Instant;
-use std::cmp::max;
-use std::io;
-use std::io::Write;
-use core::borrow::Borrow;
-use crate::osc::{Osc, Shape};
+use cpal::{EventLoop, StreamData};
+use clap::ArgMatches;
+use crate::midi::{load_midi, load_midi_lenient, Midi, MidiPlayback};
+use crate::cli::PlaybackOptions;
+use crate::render::{Renderer, render_wav};
+use crate::tempo::TimeDivision;
+use crate::meta::TextKind;
+use crate::karaoke::{Lyrics, LyricsDisplay};
+use crate::wav::SampleFormat;
+use crate::output::{Output, choose_format, find_device, indexed_format};
+use crate::control::{Command, Target, parse_time};
 use std::process::exit;
-use std::fs::File;
+use std::io::Write;
 use std::path::Path;
-use std::ffi::OsStr;
-use crate::synth::{Synth, Preset};
-use crate::filter::Mode;
 
 #[macro_use]
 extern crate rand_derive;
+#[macro_use]
+extern crate clap;
 
 mod osc;
 mod sampler;
@@ -27,100 +28,318 @@ mod midi;
 mod effects;
 mod env;
 mod synth;
+mod tempo;
+mod smf;
+mod drums;
+mod bank;
+mod wav;
+mod render;
+mod cli;
+mod output;
+mod control;
+mod meta;
+mod karaoke;
+mod sysex;
 
 
 fn main() {
-    let event_loop = EventLoop::new();
-    let out = default_output_device().expect("no output device");
-    let format = out.default_output_format().expect("no output format");
+    let matches = cli::app().get_matches();
+
+    let result = match matches.subcommand() {
+        ("play", Some(m)) => play(m),
+        ("render", Some(m)) => render(m),
+        ("info", Some(m)) => info(m),
+        ("devices", Some(_)) => list_devices(),
+        _ => Ok(()),
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        exit(1);
+    }
+}
+
+fn load(matches: &ArgMatches) -> Result<Midi, String> {
+    let path = Path::new(matches.value_of("FILE").unwrap());
+    let midi = if matches.is_present("lenient") {
+        load_midi_lenient(path)
+    } else {
+        load_midi(path)
+    };
+
+    let midi = midi.map_err(|e| format!("cannot load {}: {}", path.display(), e))?;
+    for warning in midi.warnings.iter() {
+        eprintln!("warning: {}", warning);
+    }
+
+    Ok(midi)
+}
+
+/// Formats musical position at song time as `bar:beat`, empty for files
+/// without beats. The time is measured from the start of the pattern when
+/// only one pattern is played.
+fn position(midi: &Midi, pattern: Option<usize>, micros: f64) -> String {
+    let bar_beat = match pattern {
+        Some(index) => midi.pattern_bar_beat(index, micros),
+        None => midi.bar_beat(micros),
+    };
+    bar_beat.map(|x| x.to_string()).unwrap_or_default()
+}
+
+/// Formats time in microseconds as `m:ss.mmm`.
+fn format_time(micros: f64) -> String {
+    let millis = (micros / 1000.0).round() as u64;
+    format!("{}:{:02}.{:03}", millis / 60_000, millis / 1000 % 60, millis % 1000)
+}
+
+fn info(matches: &ArgMatches) -> Result<(), String> {
+    let midi = load(matches)?;
+
+    println!("name:          {}", midi.name);
+    println!("format:        {:?}", midi.format);
+    match midi.time_division {
+        TimeDivision::Ppqn(ppqn) => println!("time division: {} ticks per quarter note", ppqn),
+        TimeDivision::Smpte { fps, ticks_per_frame } => println!("time division: {:.2} fps, {} ticks per frame", fps, ticks_per_frame),
+    }
+    if let Some(offset) = midi.smpte_offset {
+        println!("smpte offset:  {:02}:{:02}:{:02}:{:02}.{:02} ({}, informational)", offset.hours, offset.minutes, offset.seconds,
+                 offset.frames, offset.subframes, format_time(offset.to_micros()));
+    }
+    println!("length:        {}", format_time(midi.total_time));
+
+    /* patterns of format 2 files have their own tempo maps */
+    if midi.patterns() == 0 {
+        println!("tempo:");
+        for change in midi.tempo_map.changes() {
+            println!("  {:>10}  tick {:>8}  {:.2} bpm", format_time(change.time), change.tick, change.bpm());
+        }
+    }
+    for (index, track) in midi.tracks.iter().enumerate().filter(|_| midi.patterns() > 0) {
+        println!("pattern {} tempo:", index);
+        for change in track.tempo_map.as_ref().unwrap_or(&midi.tempo_map).changes() {
+            println!("  {:>10}  tick {:>8}  {:.2} bpm", format_time(track.start + change.time), change.tick, change.bpm());
+        }
+    }
+
+    if !midi.time_signatures.is_empty() {
+        println!("time signatures:");
+    }
+    for x in midi.time_signatures.iter() {
+        println!("  {:>10}  {:>8}  {}", format_time(x.time), position(&midi, None, x.time), x.value);
+    }
+
+    if !midi.key_signatures.is_empty() {
+        println!("key signatures:");
+    }
+    for x in midi.key_signatures.iter() {
+        println!("  {:>10}  {:>8}  {}", format_time(x.time), position(&midi, None, x.time), x.value);
+    }
+
+    let marks: Vec<_> = midi.texts.iter()
+        .filter(|x| x.value.kind == TextKind::Marker || x.value.kind == TextKind::CuePoint)
+        .collect();
+    if !marks.is_empty() {
+        println!("markers:");
+    }
+    for x in marks {
+        let kind = if x.value.kind == TextKind::Marker { "marker" } else { "cue" };
+        println!("  {:>10}  {:>8}  {:<6}  {}", format_time(x.time), position(&midi, None, x.time), kind, x.value.text.trim());
+    }
+
+    if let Some(lyrics) = Lyrics::from_midi(&midi) {
+        for title in lyrics.title() {
+            println!("title:         {}", title);
+        }
+        println!("lyrics:        {} lines", lyrics.lines.len());
+    }
+    println!("lyric events:  {}", midi.texts(TextKind::Lyric).count());
+    println!("texts:         {} events", midi.texts(TextKind::Text).count());
+
+    println!("tracks:");
+    for (idx, track) in midi.tracks.iter().enumerate() {
+        println!("  {:>3}  {:>6} events  {:>10}  {}", idx, track.events.len(), format_time(track.length), track.name().unwrap_or(""));
+    }
+
+    Ok(())
+}
+
+fn list_devices() -> Result<(), String> {
+    let devices = output::devices();
+    if devices.is_empty() {
+        return Err("no output device available".to_string());
+    }
+
+    for (idx, (device, is_default)) in devices.iter().enumerate() {
+        println!("{:>2}  {}{}", idx, device.name(), if *is_default { " (default)" } else { "" });
+
+        match output::supported_formats(device) {
+            Ok(formats) => for (idx, f) in formats.iter().enumerate() {
+                println!("      {:>2}  {} ch  {:?}  {} - {} Hz", idx, f.channels, f.data_type, f.min_sample_rate.0, f.max_sample_rate.0);
+            },
+            Err(e) => println!("      {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+fn render(matches: &ArgMatches) -> Result<(), String> {
+    let midi = load(matches)?;
+    let options = PlaybackOptions::new(matches);
+    let sample_rate = options.sample_rate.unwrap_or(44100) as f64;
+
+    let mut player = options.player(&midi)?;
+    let mut playback = MidiPlayback::with_polyphony(sample_rate, options.polyphony);
+    options.apply(&mut player, &mut playback)?;
+
+    let mut renderer = Renderer::new(player, playback, sample_rate);
+    renderer.seek(options.start);
+
+    let format = match matches.value_of("format") {
+        Some("pcm24") => SampleFormat::Pcm24,
+        Some("float32") => SampleFormat::Float32,
+        _ => SampleFormat::Pcm16,
+    };
+    let channels = if matches.is_present("mono") { 1 } else { 2 };
+
+    let output = matches.value_of("output").unwrap();
+    let stats = render_wav(renderer, Path::new(output), channels, format)
+        .map_err(|e| format!("cannot render {}: {}", output, e))?;
+
+    println!("rendered {} to {}", format_time(stats.duration * 1_000_000.0), output);
+    if stats.truncated {
+        eprintln!("warning: output was cut off at the 4 GiB limit of WAV files");
+    }
+    println!("peak {:.2}, {} frames clipped before limiter", stats.master.peak, stats.master.clipped);
+    Ok(())
+}
+
+/// Toggles mute or solo of a channel or track during playback.
+fn toggle(renderer: &mut Renderer, target: Target, solo: bool) {
+    match target {
+        Target::Channel(ch) => {
+            let playback = renderer.playback_mut();
+            if solo {
+                playback.set_solo(ch, !playback.is_soloed(ch));
+            } else {
+                playback.set_mute(ch, !playback.is_muted(ch));
+            }
+        }
+        Target::Track(track) if track < renderer.player_mut().tracks() => {
+            let player = renderer.player_mut();
+            if solo {
+                player.set_solo(track, !player.is_soloed(track));
+            } else {
+                player.set_mute(track, !player.is_muted(track));
+            }
+        }
+        Target::Track(track) => eprintln!("no track {}", track),
+    }
+}
+
+fn play(matches: &ArgMatches) -> Result<(), String> {
+    let midi = load(matches)?;
+    let options = PlaybackOptions::new(matches);
+
+    let out = find_device(matches.value_of("device"))?;
+
+    let data_type = match matches.value_of("sample-format") {
+        Some("f32") => Some(cpal::SampleFormat::F32),
+        Some("i16") => Some(cpal::SampleFormat::I16),
+        Some("u16") => Some(cpal::SampleFormat::U16),
+        _ => None,
+    };
+    let channels = value_t!(matches, "channels", u16).ok();
+    let format = match value_t!(matches, "device-format", usize) {
+        Ok(index) => indexed_format(&out, index, options.sample_rate)?,
+        Err(_) => choose_format(&out, channels, data_type, options.sample_rate)?,
+    };
+
+    /* cpal 0.9 always uses the buffer size chosen by the backend */
+    if matches.is_present("buffer-size") {
+        eprintln!("warning: the audio backend does not support setting buffer size, using the device default");
+    }
+
     println!("out_device={}", out.name());
     println!("out_channels={}", format.channels);
     println!("out_sample_rate={}", format.sample_rate.0);
     println!("out_data_type={:?}", format.data_type);
 
-    let mut playback = MidiPlayback::new(format.sample_rate.0 as f64);
+    let sample_rate = format.sample_rate.0 as f64;
+    let mut player = options.player(&midi)?;
+    let mut playback = MidiPlayback::with_polyphony(sample_rate, options.polyphony);
+    options.apply(&mut player, &mut playback)?;
 
-    //for x in std::fs::read_dir(".").unwrap() {
-    //    let x = x.unwrap().path();
-    //    if x.extension().is_some() && (x.extension().unwrap() == OsStr::new("mid") || x.extension().unwrap() == OsStr::new("MID")) {
-    //        load_midi(x.as_path());
-    //    }
-    //}
+    let mut renderer = Renderer::new(player, playback, sample_rate);
+    renderer.seek(options.start);
 
-    let mut midi = load_midi(Path::new("tet.mid"));
-    let mut player = Player::new(&midi);
-    // println!("{:#?}", midi);
+    if let Some(values) = matches.values_of("loop") {
+        let times: Vec<f64> = values.filter_map(parse_time).collect();
+        if times[0] >= times[1] {
+            return Err("loop end has to be after its start".to_string());
+        }
+        renderer.set_loop(Some((times[0], times[1])));
+    }
 
+    let event_loop = EventLoop::new();
     let stream = event_loop
         .build_output_stream(&out, &format)
-        .expect("cannot create output stream");
+        .map_err(|e| format!("cannot create output stream: {}", e))?;
     event_loop.play_stream(stream);
 
-    let mut export: Vec<f32> = vec![];
-    let mut start = Instant::now();
-    let mut frames = 0;
-
-    event_loop.run(|_stream_id, _stream_data| {
-        /* playback */
-        let now = start.elapsed().as_micros();
-        for event in player.get_events(now as f64) {
-            match event.kind {
-                Kind::NoteOn { ch, note, velocity } => {
-                    //println!("note_on {} {}", note, velocity);
-                    playback.note_on(ch, note, velocity)
-                }
-                Kind::NoteOff { ch, note } => {
-                    //println!("note_off {}", note);
-                    playback.note_off(ch, note)
-                }
-                Kind::Instrument { ch, instrument } => {
-                    //println!("instrument ch={} p={}", ch, instrument.program_number());
-                    playback.set_instrument(ch, instrument)
-                }
-            }
+    let mut output = Output::new(format.channels as usize);
+    let mut buffers = 0;
+
+    println!("{}", control::HELP);
+    let commands = control::spawn();
+    let midi = &midi;
+    let pattern = options.pattern;
+
+    let lyrics = if matches.is_present("no-lyrics") { None } else { Lyrics::from_midi(midi) };
+    if let Some(ref lyrics) = lyrics {
+        for title in lyrics.title() {
+            println!("{}", title);
         }
+    }
+    let mut lyrics = lyrics.as_ref().map(LyricsDisplay::new);
 
-        if frames % 100 == 0 {
-            let (a, b) = playback.voices();
-            println!("vo {}/{}", b, a);
+    event_loop.run(move |_stream_id, _stream_data| {
+        while let Ok(command) = commands.try_recv() {
+            match command {
+                Command::TogglePause if renderer.is_paused() => renderer.resume(),
+                Command::TogglePause => renderer.pause(),
+                Command::Seek(time) => renderer.seek(time),
+                Command::Marker(name) => match midi.find_marker(&name) {
+                    Some(marker) => renderer.seek(marker.time),
+                    None => eprintln!("no marker named '{}'", name),
+                },
+                Command::Loop(range) => renderer.set_loop(range),
+                Command::Tempo(speed) => renderer.set_speed(speed),
+                Command::Transpose(semitones) => renderer.playback_mut().set_transpose(semitones),
+                Command::ToggleMute(target) => toggle(&mut renderer, target, false),
+                Command::ToggleSolo(target) => toggle(&mut renderer, target, true),
+                Command::Quit => exit(0),
+            }
         }
 
-        frames += 1;
-
-        /* generate data */
-        match _stream_data {
-            StreamData::Output { buffer: UnknownTypeOutputBuffer::F32(mut buffer) } => {
-                for elem in buffer.chunks_mut(2) {
-                    let v = playback.next();
-
-                    /* exporting file */
-                    export.push(v as f32);
-                    if export.len() == 60 * format.sample_rate.0 as usize {
-                        println!("saving...");
-                        let mut f = File::create("export.raw").unwrap();
-                        for num in export.iter() {
-                            let i = num.to_bits();
-                            #[inline]
-                            fn u32tou8abe(v: u32) -> [u8; 4] {
-                                [
-                                    (v >> 24) as u8,
-                                    (v >> 16) as u8,
-                                    (v >> 8) as u8,
-                                    v as u8,
-                                ]
-                            }
-
-                            f.write(&u32tou8abe(i));
-                        }
-                        f.flush();
-                        exit(0);
-                    }
-
-                    elem[0] = v as f32;
-                    elem[1] = v as f32;
-                }
+        /* lyrics replace the status line */
+        if let Some(ref mut lyrics) = lyrics {
+            if let Some(text) = lyrics.update(renderer.song_time()) {
+                print!("{}", text);
+                std::io::stdout().flush().ok();
             }
-            _ => (),
+        } else if buffers % 100 == 0 && !renderer.is_paused() {
+            let (a, b) = renderer.playback().voices();
+            let stats = renderer.playback().master().stats();
+            let time = renderer.song_time();
+            println!("{} {} vo {}/{} clipped {} peak {:.2}", format_time(time), position(midi, pattern, time), b, a, stats.clipped, stats.peak);
+        }
+
+        buffers += 1;
+
+        /* generate data, events are scheduled per frame by the renderer */
+        if let StreamData::Output { buffer } = _stream_data {
+            output.fill(buffer, || renderer.next());
         }
     });
 }
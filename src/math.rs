@@ -1,3 +1,11 @@
+/// Returns `(left, right)` gains of equal power panning for pan position
+/// (-1.0 hard left, 1.0 hard right), the center keeps unity gain.
+pub fn pan_gains(pan: f64) -> (f64, f64) {
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * std::f64::consts::FRAC_PI_4;
+    let norm = std::f64::consts::SQRT_2;
+    (angle.cos() * norm, angle.sin() * norm)
+}
+
 pub struct Fraction {
     numerator: usize,
     denominator: usize,
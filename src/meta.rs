@@ -0,0 +1,189 @@
+use std::fmt;
+
+/// Meta event of a midi file placed on the song timeline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timed<T> {
+    pub value: T,
+    /// Index of the track the event comes from.
+    pub track: usize,
+    /// Absolute time in ticks from the start of the track.
+    pub tick: u64,
+    /// Song time in microseconds, resolved through the tempo map.
+    pub time: f64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TimeSignature {
+    pub numerator: u8,
+    /// Note value of one beat, 4 is a quarter note.
+    pub denominator: u16,
+    /// Midi clocks (24 per quarter note) in one metronome click.
+    pub clocks_per_click: u8,
+    /// Notated 32nd notes in one quarter note.
+    pub notated_32nds: u8,
+}
+
+impl TimeSignature {
+    /// Time signature assumed until the first TimeSignature event.
+    pub const DEFAULT: TimeSignature = TimeSignature {
+        numerator: 4,
+        denominator: 4,
+        clocks_per_click: 24,
+        notated_32nds: 8,
+    };
+
+    /// Parses data of the TimeSignature meta event (`nn dd cc bb`), the
+    /// denominator is stored as a power of two.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 4 || data[0] == 0 || data[1] > 15 {
+            return None;
+        }
+
+        Some(TimeSignature {
+            numerator: data[0],
+            denominator: 1 << data[1],
+            clocks_per_click: data[2],
+            notated_32nds: data[3],
+        })
+    }
+
+    #[inline]
+    pub fn ticks_per_beat(&self, ppqn: u16) -> u64 {
+        (ppqn as u64 * 4 / self.denominator as u64).max(1)
+    }
+
+    #[inline]
+    pub fn ticks_per_bar(&self, ppqn: u16) -> u64 {
+        self.ticks_per_beat(ppqn) * self.numerator as u64
+    }
+}
+
+impl fmt::Display for TimeSignature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.numerator, self.denominator)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct KeySignature {
+    /// Number of sharps (positive) or flats (negative), -7 to 7.
+    pub sharps: i8,
+    pub minor: bool,
+}
+
+impl KeySignature {
+    /// Parses data of the KeySignature meta event (`sf mi`).
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 2 || data[1] > 1 {
+            return None;
+        }
+
+        let sharps = data[0] as i8;
+        if !(-7..=7).contains(&sharps) {
+            return None;
+        }
+        Some(KeySignature { sharps, minor: data[1] == 1 })
+    }
+
+    /// Name of the tonic, e.g. `Eb` for three flats in major.
+    pub fn tonic(&self) -> &'static str {
+        const MAJOR: [&str; 15] = ["Cb", "Gb", "Db", "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#"];
+        const MINOR: [&str; 15] = ["Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#", "G#", "D#", "A#"];
+
+        let index = (self.sharps + 7) as usize;
+        if self.minor { MINOR[index] } else { MAJOR[index] }
+    }
+}
+
+impl fmt::Display for KeySignature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.tonic(), if self.minor { "minor" } else { "major" })
+    }
+}
+
+/// Kind of a text meta event.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TextKind {
+    Text,
+    Lyric,
+    Marker,
+    CuePoint,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Text {
+    pub kind: TextKind,
+    pub text: String,
+}
+
+/// Musical position, bars and beats are numbered from 1.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BarBeat {
+    pub bar: u64,
+    pub beat: u64,
+    /// Ticks from the start of the beat.
+    pub tick: u64,
+}
+
+impl BarBeat {
+    /// Returns position of the tick in a song with specified time signature
+    /// changes sorted by tick. A signature which does not start on a bar
+    /// line starts a new bar.
+    pub fn at(tick: u64, signatures: &[Timed<TimeSignature>], ppqn: u16) -> Self {
+        let mut bars = 0;
+        let mut start = 0;
+        let mut signature = TimeSignature::DEFAULT;
+
+        for change in signatures.iter().take_while(|x| x.tick <= tick) {
+            let ticks_per_bar = signature.ticks_per_bar(ppqn);
+            bars += (change.tick - start + ticks_per_bar - 1) / ticks_per_bar;
+            start = change.tick;
+            signature = change.value;
+        }
+
+        let ticks = tick - start;
+        let ticks_per_beat = signature.ticks_per_beat(ppqn);
+        let in_bar = ticks % signature.ticks_per_bar(ppqn);
+
+        BarBeat {
+            bar: bars + ticks / signature.ticks_per_bar(ppqn) + 1,
+            beat: in_bar / ticks_per_beat + 1,
+            tick: in_bar % ticks_per_beat,
+        }
+    }
+}
+
+impl fmt::Display for BarBeat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.bar, self.beat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::meta::{BarBeat, KeySignature, TimeSignature, Timed};
+
+    #[test]
+    fn signatures_and_positions() {
+        assert_eq!(KeySignature::parse(&[0xfd, 0]).unwrap().to_string(), "Eb major");
+        assert_eq!(KeySignature::parse(&[2, 1]).unwrap().to_string(), "B minor");
+        assert_eq!(KeySignature::parse(&[8, 0]), None);
+
+        let three_four = TimeSignature::parse(&[3, 2, 24, 8]).unwrap();
+        let six_eight = TimeSignature::parse(&[6, 3, 36, 8]).unwrap();
+        assert_eq!(six_eight.to_string(), "6/8");
+
+        /* 2 bars of 4/4, 3/4 from bar 3 and 6/8 from bar 5 at 100 ppqn */
+        let signatures = [
+            Timed { value: three_four, track: 0, tick: 800, time: 0.0 },
+            Timed { value: six_eight, track: 0, tick: 1400, time: 0.0 },
+        ];
+        let at = |tick| BarBeat::at(tick, &signatures, 100);
+
+        assert_eq!(at(0), BarBeat { bar: 1, beat: 1, tick: 0 });
+        assert_eq!(at(750), BarBeat { bar: 2, beat: 4, tick: 50 });
+        assert_eq!(at(1000), BarBeat { bar: 3, beat: 3, tick: 0 });
+        assert_eq!(at(1400), BarBeat { bar: 5, beat: 1, tick: 0 });
+        assert_eq!(at(1650).to_string(), "5:6");
+    }
+}
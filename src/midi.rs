@@ -1,19 +1,23 @@
 use ghakuf::messages::*;
 use ghakuf::reader::*;
+use std::{fmt, io};
 use std::path;
 use std::path::Path;
 use ghakuf::formats::Format;
-use std::slice::Iter;
-use std::iter::Peekable;
-use crate::synth::{Preset, Synth};
-use crate::osc::Shape;
-use crate::filter::Mode;
+use crate::synth::{Preset, Synth, Semitone, StealPolicy, DEFAULT_POLYPHONY};
+use crate::drums::DrumKit;
+use crate::bank::{PresetBank, Bank};
+use crate::tempo::{TempoMap, TimeDivision, SmpteTime};
+use crate::smf::scan;
+use crate::effects::MasterBus;
+use crate::meta::{Timed, TimeSignature, KeySignature, Text, TextKind, BarBeat};
+use crate::sysex::SysEx;
 
 pub fn note2freq(note: f64) -> f64 {
     return 440.0 * 2.0f64.powf((note - 69.0) / 12.0);
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum GMFamily {
     Piano,
     ChromaticPercussion,
@@ -40,65 +44,291 @@ pub struct GMInstrument {
 }
 
 impl GMInstrument {
-    fn new(program_number: u8) -> Self {
-        GMInstrument {
+    /// Returns `None` when the program number is not a valid data byte.
+    pub fn new(program_number: u8) -> Option<Self> {
+        Some(GMInstrument {
             program_number,
             family: match program_number {
                 0...7 => GMFamily::Piano,
                 8...15 => GMFamily::ChromaticPercussion,
                 16...23 => GMFamily::Organ,
-                14...31 => GMFamily::Guitar,
+                24...31 => GMFamily::Guitar,
                 32...39 => GMFamily::Bass,
                 40...47 => GMFamily::Strings,
                 48...55 => GMFamily::Ensemble,
                 56...63 => GMFamily::Brass,
                 64...71 => GMFamily::Reed,
                 72...79 => GMFamily::Pipe,
-                80...88 => GMFamily::SynthLead,
+                80...87 => GMFamily::SynthLead,
                 88...95 => GMFamily::SynthPad,
                 96...103 => GMFamily::SynthEffects,
                 104...111 => GMFamily::Ethnic,
                 112...119 => GMFamily::Percussive,
                 120...127 => GMFamily::SoundEffects,
-                _ => panic!("invalid program number {}", program_number)
+                _ => return None,
             },
-        }
+        })
     }
 
     #[inline]
     pub fn program_number(&self) -> u8 {
         return self.program_number;
     }
+
+    #[inline]
+    pub fn family(&self) -> GMFamily {
+        return self.family;
+    }
+}
+
+/// Error found while loading a midi file. In lenient mode the recoverable
+/// ones are collected in `Midi::warnings` instead.
+#[derive(Debug)]
+pub enum MidiError {
+    /// File cannot be opened or read.
+    Io(io::Error),
+    /// Header chunk is missing or invalid.
+    MalformedHeader,
+    /// Chunk that is not a track was found where a track was expected.
+    InvalidChunk { tag: [u8; 4] },
+    /// Track ends (or its declared length ends) in the middle of an event.
+    TruncatedTrack { track: usize },
+    /// Status byte that cannot start an event in a track.
+    InvalidStatus { track: usize, status: u8 },
+    /// Data byte of a channel message with its high bit set.
+    InvalidDataByte { track: usize, byte: u8 },
+    /// Meta event with too short data.
+    MalformedMetaEvent { track: usize },
+    /// Event found before the first track chunk.
+    EventBeforeTrack,
+    /// System exclusive message continued in an F7 packet, the reader loses
+    /// track of the events after it.
+    SplitSysEx { track: usize },
+}
+
+impl fmt::Display for MidiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MidiError::Io(e) => write!(f, "i/o error: {}", e),
+            MidiError::MalformedHeader => write!(f, "malformed header chunk"),
+            MidiError::InvalidChunk { tag } => write!(f, "invalid chunk {:?}", String::from_utf8_lossy(tag)),
+            MidiError::TruncatedTrack { track } => write!(f, "track {} is truncated", track),
+            MidiError::InvalidStatus { track, status } => write!(f, "invalid status byte {:#04x} in track {}", status, track),
+            MidiError::InvalidDataByte { track, byte } => write!(f, "invalid data byte {:#04x} in track {}", byte, track),
+            MidiError::MalformedMetaEvent { track } => write!(f, "malformed meta event in track {}", track),
+            MidiError::EventBeforeTrack => write!(f, "event before the first track"),
+            MidiError::SplitSysEx { track } => write!(f, "system exclusive message split into packets in track {} is not supported", track),
+        }
+    }
+}
+
+impl MidiError {
+    fn in_track(self, index: usize) -> Self {
+        match self {
+            MidiError::TruncatedTrack { .. } => MidiError::TruncatedTrack { track: index },
+            MidiError::InvalidStatus { status, .. } => MidiError::InvalidStatus { track: index, status },
+            e => e,
+        }
+    }
+}
+
+impl std::error::Error for MidiError {}
+
+impl From<io::Error> for MidiError {
+    fn from(e: io::Error) -> Self {
+        MidiError::Io(e)
+    }
 }
 
 #[derive(Debug)]
 pub struct Midi {
     pub tracks: Vec<Track>,
-    pub time_division: u16,
-    pub mpqn: u128,
-    pub tick_length: f64,
+    pub time_division: TimeDivision,
+    /// Tempo map shared by all tracks, format 2 files use the map of the
+    /// first pattern here and each pattern has its own in `Track::tempo_map`.
+    pub tempo_map: TempoMap,
+    /// Start time of the song on the SMPTE timeline, if specified. It is
+    /// informational only, song times start at tick 0 and playback is not
+    /// delayed by it.
+    pub smpte_offset: Option<SmpteTime>,
+    /// Length of the song in microseconds (time of the last event).
     pub total_time: f64,
     pub name: String,
     pub format: Format,
+    pub time_signatures: Vec<Timed<TimeSignature>>,
+    pub key_signatures: Vec<Timed<KeySignature>>,
+    /// Text, lyric, marker and cue point events of all tracks ordered by time.
+    pub texts: Vec<Timed<Text>>,
+    /// Problems that were recovered from when loaded in lenient mode, and
+    /// malformed signatures which are skipped in both modes.
+    pub warnings: Vec<MidiError>,
 }
 
 impl Midi {
     pub fn new(name: String) -> Self {
         Midi {
+            warnings: vec![],
             format: Format::Unknown,
-            mpqn: 500000,
-            tick_length: 0.0,
+            tempo_map: TempoMap::new(TimeDivision::Ppqn(0), &[]),
+            smpte_offset: None,
             total_time: 0.0,
-            time_division: 0,
+            time_division: TimeDivision::Ppqn(0),
             tracks: vec![],
+            time_signatures: vec![],
+            key_signatures: vec![],
+            texts: vec![],
             name,
         }
     }
+
+    /// Rebuilds the tempo map from collected `(track, tick, mpqn)` tempo
+    /// changes and recomputes time of all events from their ticks.
+    fn apply_tempo_map(&mut self, tempos: &[(usize, u64, u32)]) {
+        let first = self.tracks.first().map(|x| x.id);
+        let all: Vec<(u64, u32)> = tempos.iter()
+            .filter(|&&(t, _, _)| self.format != Format::F2 || Some(t) == first)
+            .map(|&(_, tick, mpqn)| (tick, mpqn))
+            .collect();
+        self.tempo_map = TempoMap::new(self.time_division, &all);
+        self.total_time = 0.0;
+
+        for track in self.tracks.iter_mut() {
+            track.start = if self.format == Format::F2 { self.total_time } else { 0.0 };
+
+            // format 2 patterns are independent and each has its own tempo
+            if self.format == Format::F2 {
+                let own: Vec<(u64, u32)> = tempos.iter()
+                    .filter(|&&(t, _, _)| t == track.id)
+                    .map(|&(_, tick, mpqn)| (tick, mpqn))
+                    .collect();
+                track.tempo_map = Some(TempoMap::new(self.time_division, &own));
+            }
+
+            let tempo_map = track.tempo_map.as_ref().unwrap_or(&self.tempo_map);
+            for event in track.events.iter_mut() {
+                event.time = tempo_map.tick_to_micros(event.tick);
+                track.end_tick = track.end_tick.max(event.tick);
+            }
+            track.length = tempo_map.tick_to_micros(track.end_tick);
+
+            // patterns are played one after another
+            if self.format == Format::F2 {
+                self.total_time += track.length;
+            } else {
+                self.total_time = self.total_time.max(track.length);
+            }
+        }
+
+        /* meta events are placed on the song timeline like in `Player` */
+        let tracks = &self.tracks;
+        let tempo_map = &self.tempo_map;
+        let time = |track: usize, tick: u64| {
+            let track = &tracks[track];
+            track.start + track.tempo_map.as_ref().unwrap_or(tempo_map).tick_to_micros(tick)
+        };
+
+        for x in self.time_signatures.iter_mut() {
+            x.time = time(x.track, x.tick);
+        }
+        for x in self.key_signatures.iter_mut() {
+            x.time = time(x.track, x.tick);
+        }
+        for x in self.texts.iter_mut() {
+            x.time = time(x.track, x.tick);
+        }
+
+        self.time_signatures.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+        self.key_signatures.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+        self.texts.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+    }
+
+    /// Returns musical position at specified song time, files with SMPTE
+    /// time division have no beats. Format 2 files count bars from the
+    /// start of the pattern which plays at that time.
+    pub fn bar_beat(&self, time_micros: f64) -> Option<BarBeat> {
+        if self.format == Format::F2 {
+            let index = self.tracks.iter().rposition(|x| x.start <= time_micros).unwrap_or(0);
+            let start = self.tracks.get(index).map_or(0.0, |x| x.start);
+            return self.pattern_bar_beat(index, time_micros - start);
+        }
+
+        match self.time_division {
+            TimeDivision::Ppqn(ppqn) => {
+                let tick = self.tempo_map.micros_to_tick(time_micros).max(0.0) as u64;
+                Some(BarBeat::at(tick, &self.time_signatures, ppqn))
+            }
+            TimeDivision::Smpte { .. } => None,
+        }
+    }
+
+    /// Returns musical position at specified time from the start of a
+    /// format 2 pattern, using its own tempo map and time signatures.
+    pub fn pattern_bar_beat(&self, index: usize, time_micros: f64) -> Option<BarBeat> {
+        let track = self.tracks.get(index)?;
+        match self.time_division {
+            TimeDivision::Ppqn(ppqn) => {
+                let tempo_map = track.tempo_map.as_ref().unwrap_or(&self.tempo_map);
+                let tick = tempo_map.micros_to_tick(time_micros).max(0.0) as u64;
+                let signatures: Vec<_> = self.time_signatures.iter()
+                    .filter(|x| x.track == index)
+                    .cloned()
+                    .collect();
+                Some(BarBeat::at(tick, &signatures, ppqn))
+            }
+            TimeDivision::Smpte { .. } => None,
+        }
+    }
+
+    /// Returns texts of specified kind in order of time.
+    pub fn texts(&self, kind: TextKind) -> impl Iterator<Item=&Timed<Text>> {
+        self.texts.iter().filter(move |x| x.value.kind == kind)
+    }
+
+    /// Finds marker by its name, ignoring case. A unique beginning of the
+    /// name is enough.
+    pub fn find_marker(&self, name: &str) -> Option<&Timed<Text>> {
+        let name = name.to_lowercase();
+        let markers: Vec<&Timed<Text>> = self.texts(TextKind::Marker).collect();
+
+        if let Some(marker) = markers.iter().find(|x| x.value.text.trim().to_lowercase() == name) {
+            return Some(marker);
+        }
+
+        let mut matching = markers.into_iter().filter(|x| x.value.text.trim().to_lowercase().starts_with(&name));
+        match (matching.next(), matching.next()) {
+            (Some(marker), None) => Some(marker),
+            _ => None,
+        }
+    }
+
+    /// Number of independent sequential patterns (tracks) of a format 2 file.
+    pub fn patterns(&self) -> usize {
+        if self.format == Format::F2 { self.tracks.len() } else { 0 }
+    }
 }
 
 pub type Channel = u8;
 pub type Note = u8;
 pub type Velocity = u8;
+pub type Control = u8;
+
+pub const CC_BANK_SELECT: Control = 0;
+pub const CC_MODULATION: Control = 1;
+pub const CC_DATA_ENTRY: Control = 6;
+pub const CC_VOLUME: Control = 7;
+pub const CC_BALANCE: Control = 8;
+pub const CC_PAN: Control = 10;
+pub const CC_EXPRESSION: Control = 11;
+pub const CC_BANK_SELECT_LSB: Control = 32;
+pub const CC_DATA_ENTRY_LSB: Control = 38;
+pub const CC_SUSTAIN: Control = 64;
+pub const CC_NRPN_LSB: Control = 98;
+pub const CC_NRPN_MSB: Control = 99;
+pub const CC_RPN_LSB: Control = 100;
+pub const CC_RPN_MSB: Control = 101;
+pub const CC_ALL_SOUND_OFF: Control = 120;
+pub const CC_RESET_ALL_CONTROLLERS: Control = 121;
+pub const CC_ALL_NOTES_OFF: Control = 123;
 
 #[derive(Debug)]
 pub enum Kind {
@@ -115,11 +345,37 @@ pub enum Kind {
         ch: Channel,
         instrument: GMInstrument,
     },
+    Controller {
+        ch: Channel,
+        control: Control,
+        value: u8,
+    },
+    PitchBend {
+        ch: Channel,
+        /// Bend from -8192 to 8191, 0 is center.
+        value: i16,
+    },
+    ChannelPressure {
+        ch: Channel,
+        pressure: u8,
+    },
+    KeyPressure {
+        ch: Channel,
+        note: Note,
+        pressure: u8,
+    },
+    /// System exclusive message without the `F0` and `F7` bytes.
+    SysEx {
+        data: Vec<u8>,
+    },
 }
 
 #[derive(Debug)]
 pub struct Event {
     pub kind: Kind,
+    /// Absolute time in ticks from the start of the track.
+    pub tick: u64,
+    /// Absolute time in microseconds, resolved through the tempo map.
     pub time: f64,
 }
 
@@ -128,80 +384,182 @@ pub struct Track {
     name: Option<String>,
     id: usize,
     pub events: Vec<Event>,
+    /// Absolute tick of the EndOfTrack event.
+    pub end_tick: u64,
+    /// Length of the track in microseconds including trailing rest.
+    pub length: f64,
+    /// Song time where the track starts, format 2 patterns are played one
+    /// after another.
+    pub start: f64,
+    /// Own tempo map of a format 2 pattern, other formats share `Midi::tempo_map`.
+    pub tempo_map: Option<TempoMap>,
 }
 
-struct MidiReader {
-    midi: Midi
+impl Track {
+    #[inline]
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
 }
 
-impl Handler for MidiReader {
-    fn header(&mut self, format: u16, tracks: u16, time_division: u16) {
-        println!("name={} format={} tracks={} time_division={}", self.midi.name, format, tracks, time_division);
+struct MidiReader {
+    midi: Midi,
+    /// Absolute tick of the last event in current track.
+    tick: u64,
+    /// Tempo changes `(track, tick, mpqn)` collected from all tracks.
+    tempos: Vec<(usize, u64, u32)>,
+    lenient: bool,
+    /// Number of readable events of each broken track, the rest is skipped.
+    limits: Vec<Option<usize>>,
+    /// Raw pitch bend values of each track, see `smf::scan`.
+    pitch_bends: Vec<Vec<u16>>,
+    /// Number of pitch bend events read in current track.
+    bends: usize,
+    /// Number of events read in current track.
+    events: usize,
+    error: Option<MidiError>,
+}
 
-        self.midi.time_division = time_division; // ppqn
-        self.midi.tick_length = self.midi.mpqn as f64 / self.midi.time_division as f64;
-        self.midi.format = Format::new(format);
+impl MidiReader {
+    /// Records a problem, returns whether the reading can continue.
+    fn report(&mut self, error: MidiError) -> bool {
+        if self.lenient {
+            self.midi.warnings.push(error);
+            return true;
+        }
 
-        // If bit 15 of <time_division> is a one, delta times in a file correspond to
-        // subdivisions of a second, in a way consistent with SMPTE and MIDI Time Code.
-        if time_division & 0x8000 == 0x8000 {
-            eprintln!("SMPTE not supported!");
+        if self.error.is_none() {
+            self.error = Some(error);
         }
+        false
+    }
 
-        // the file contains one or more sequentially independent single-track patterns
-        if format == 2 {
-            eprintln!("Format 2 Midi files are not supported!");
+    /// Advances time of current track, returns whether the event can be processed.
+    fn begin_event(&mut self, delta_time: u32) -> bool {
+        self.tick += delta_time as u64;
+        self.events += 1;
+
+        if self.midi.tracks.is_empty() {
+            self.report(MidiError::EventBeforeTrack);
+            return false;
         }
+        true
+    }
+
+    #[inline]
+    fn track_index(&self) -> usize {
+        self.midi.tracks.len().max(1) - 1
+    }
+
+    /// Text is decoded as UTF-8, older files (mostly karaoke) use Latin-1.
+    fn text(&mut self, kind: TextKind, data: &[u8]) {
+        let text = match std::str::from_utf8(data) {
+            Ok(text) => text.to_string(),
+            Err(_) => data.iter().map(|&x| x as char).collect(),
+        };
+        let text = Text { kind, text };
+        self.midi.texts.push(Timed { value: text, track: self.track_index(), tick: self.tick, time: 0.0 });
+    }
+}
+
+/// Returns the first data byte of the event that has its high bit set.
+fn invalid_data_byte(event: &MidiEvent) -> Option<u8> {
+    let bytes = match *event {
+        MidiEvent::NoteOff { note, velocity, .. } => [note, velocity],
+        MidiEvent::NoteOn { note, velocity, .. } => [note, velocity],
+        MidiEvent::PolyphonicKeyPressure { note, velocity, .. } => [note, velocity],
+        MidiEvent::ControlChange { control, data, .. } => [control, data],
+        MidiEvent::ProgramChange { program, .. } => [program, 0],
+        MidiEvent::ChannelPressure { pressure, .. } => [pressure, 0],
+        MidiEvent::PitchBendChange { .. } | MidiEvent::Unknown { .. } => [0, 0],
+    };
+
+    bytes.iter().cloned().find(|x| *x > 0x7f)
+}
+
+impl Handler for MidiReader {
+    fn header(&mut self, format: u16, _tracks: u16, time_division: u16) {
+        // If bit 15 of <time_division> is a one, delta times in a file correspond to
+        // subdivisions of a second, in a way consistent with SMPTE and MIDI Time Code.
+        self.midi.time_division = TimeDivision::new(time_division);
+        self.midi.format = Format::new(format);
     }
 
     /// Fired when meta event has found.
     fn meta_event(&mut self, delta_time: u32, event: &MetaEvent, data: &Vec<u8>) {
+        if !self.begin_event(delta_time) {
+            return;
+        }
+
         match event {
             MetaEvent::SequenceOrTrackName => self.midi.tracks.last_mut().unwrap().name = Some(String::from_utf8_lossy(data).to_string()),
             MetaEvent::SetTempo => {
-                // todo: tracks can change tempo during playing multiple times
-
-                let mpqn = ((data[0] as u64) << 16 | (data[1] as u64) << 8 | data[2] as u64) as u128;
-                let bpm = 60_000_000 / mpqn;
+                if data.len() < 3 {
+                    self.report(MidiError::MalformedMetaEvent { track: self.track_index() });
+                    return;
+                }
 
-                self.midi.mpqn = mpqn;
-                self.midi.tick_length = self.midi.mpqn as f64 / self.midi.time_division as f64;
-
-                println!("set_tempo {} {}bpm", delta_time, bpm)
+                let mpqn = (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+                self.tempos.push((self.midi.tracks.len() - 1, self.tick, mpqn));
+            }
+            MetaEvent::SMTPEOffset => {
+                // only the first offset counts, it should be in the first track
+                if self.midi.smpte_offset.is_none() {
+                    self.midi.smpte_offset = SmpteTime::parse(data);
+                }
             }
-            MetaEvent::SMTPEOffset => eprintln!("SMTPEOffset is not supported!"),
 
-            MetaEvent::CuePoint => {} /* ignored */
-            MetaEvent::Lyric => {} /* ignored */
-            MetaEvent::Marker => {} /* ignored */
+            /* signatures do not affect playback, bad ones are skipped even in strict mode */
+            MetaEvent::TimeSignature => match TimeSignature::parse(data) {
+                Some(value) => self.midi.time_signatures.push(Timed { value, track: self.track_index(), tick: self.tick, time: 0.0 }),
+                None => self.midi.warnings.push(MidiError::MalformedMetaEvent { track: self.track_index() }),
+            },
+            MetaEvent::KeySignature => match KeySignature::parse(data) {
+                Some(value) => self.midi.key_signatures.push(Timed { value, track: self.track_index(), tick: self.tick, time: 0.0 }),
+                None => self.midi.warnings.push(MidiError::MalformedMetaEvent { track: self.track_index() }),
+            },
+            MetaEvent::TextEvent => self.text(TextKind::Text, data),
+            MetaEvent::Lyric => self.text(TextKind::Lyric, data),
+            MetaEvent::Marker => self.text(TextKind::Marker, data),
+            MetaEvent::CuePoint => self.text(TextKind::CuePoint, data),
+
             MetaEvent::InstrumentName => {} /* ignored */
             MetaEvent::MIDIChannelPrefix => {} /* used only with instrument name, ignored */
-            MetaEvent::TextEvent => {} /* ignored */
             MetaEvent::CopyrightNotice => {} /* ignored */
             MetaEvent::SequenceNumber => {} /* ignored */
             MetaEvent::SequencerSpecificMetaEvent => {} /* ignored */
-            MetaEvent::KeySignature => {} /* ignored */
-            MetaEvent::TimeSignature => {} /* ignored */
-            MetaEvent::EndOfTrack => {} /* silent */
+            MetaEvent::EndOfTrack => self.midi.tracks.last_mut().unwrap().end_tick = self.tick,
             MetaEvent::Unknown { event_type } => {} /* silent */
         }
     }
 
     /// Fired when MIDI event has found.
     fn midi_event(&mut self, delta_time: u32, event: &MidiEvent) {
-        self.midi.total_time += (delta_time as f64 * self.midi.tick_length);
+        if !self.begin_event(delta_time) {
+            return;
+        }
+
+        if let Some(byte) = invalid_data_byte(event) {
+            /* event is dropped in lenient mode */
+            self.report(MidiError::InvalidDataByte { track: self.track_index(), byte });
+            return;
+        }
+
+        let tick = self.tick;
         let track = self.midi.tracks.last_mut().unwrap();
 
         match event {
             MidiEvent::NoteOff { ch, note, velocity } | MidiEvent::NoteOn { ch, note, velocity: velocity @ 0 } => {
                 track.events.push(Event {
-                    time: self.midi.total_time,
+                    tick,
+                    time: 0.0,
                     kind: Kind::NoteOff { note: *note, ch: *ch },
                 })
             }
             MidiEvent::NoteOn { ch, note, velocity } => {
                 track.events.push(Event {
-                    time: self.midi.total_time,
+                    tick,
+                    time: 0.0,
                     kind: Kind::NoteOn {
                         ch: *ch,
                         note: *note,
@@ -211,161 +569,1379 @@ impl Handler for MidiReader {
             }
             MidiEvent::ProgramChange { ch, program } => {
                 track.events.push(Event {
-                    time: self.midi.total_time,
+                    tick,
+                    time: 0.0,
                     kind: Kind::Instrument {
                         ch: *ch,
-                        instrument: GMInstrument::new(*program),
+                        instrument: GMInstrument::new(*program).unwrap(), /* validated above */
+                    },
+                })
+            }
+            MidiEvent::ControlChange { ch, control, data } => {
+                track.events.push(Event {
+                    tick,
+                    time: 0.0,
+                    kind: Kind::Controller {
+                        ch: *ch,
+                        control: *control,
+                        value: *data,
+                    },
+                })
+            }
+            MidiEvent::PitchBendChange { ch, .. } => {
+                let bends = self.bends;
+                let raw = self.pitch_bends.get(track.id).and_then(|x| x.get(bends).cloned());
+                self.bends += 1;
+
+                track.events.push(Event {
+                    tick,
+                    time: 0.0,
+                    kind: Kind::PitchBend {
+                        ch: *ch,
+                        value: raw.map(|x| x as i16 - 8192).unwrap_or(0),
+                    },
+                })
+            }
+            MidiEvent::ChannelPressure { ch, pressure } => {
+                track.events.push(Event {
+                    tick,
+                    time: 0.0,
+                    kind: Kind::ChannelPressure {
+                        ch: *ch,
+                        pressure: *pressure,
+                    },
+                })
+            }
+            MidiEvent::PolyphonicKeyPressure { ch, note, velocity } => {
+                track.events.push(Event {
+                    tick,
+                    time: 0.0,
+                    kind: Kind::KeyPressure {
+                        ch: *ch,
+                        note: *note,
+                        pressure: *velocity,
                     },
                 })
             }
-            MidiEvent::ControlChange { ch, control, data } => {}
-            MidiEvent::PitchBendChange { ch, data } => {}
-            MidiEvent::ChannelPressure { ch, pressure } => {} /* unsupported */
-            MidiEvent::PolyphonicKeyPressure { ch, note, velocity } => {} /* unsupported */
             MidiEvent::Unknown { .. } => {} /* silent */
         }
     }
 
+    /// Fired when system exclusive event has found.
+    fn sys_ex_event(&mut self, delta_time: u32, event: &SysExEvent, data: &Vec<u8>) {
+        if !self.begin_event(delta_time) {
+            return;
+        }
+
+        /* F7 events are escaped bytes and are not system exclusive, messages
+           split into packets are rejected by `smf::scan` */
+        let mut message = match event {
+            SysExEvent::F0 if data.last() == Some(&0xf7) => data.clone(),
+            _ => return,
+        };
+        message.pop();
+        let tick = self.tick;
+        self.midi.tracks.last_mut().unwrap().events.push(Event {
+            tick,
+            time: 0.0,
+            kind: Kind::SysEx { data: message },
+        })
+    }
+
     /// Fired when track has changed.
     fn track_change(&mut self) {
-        self.midi.total_time = 0.0;
+        self.tick = 0;
+        self.events = 0;
+        self.bends = 0;
         self.midi.tracks.push(Track {
             name: None,
             id: self.midi.tracks.len(),
             events: vec![],
+            end_tick: 0,
+            length: 0.0,
+            start: 0.0,
+            tempo_map: None,
         })
     }
+
+    /// Send handler status to parser.
+    fn status(&mut self) -> HandlerStatus {
+        if self.error.is_some() {
+            return HandlerStatus::SkipAll;
+        }
+
+        // stop before the event that would overrun a broken track
+        match self.limits.get(self.track_index()) {
+            Some(Some(limit)) if self.events >= *limit => HandlerStatus::SkipTrack,
+            _ => HandlerStatus::Continue,
+        }
+    }
+}
+
+/// Loads midi file, fails on the first problem found in the file.
+pub fn load_midi(path: &Path) -> Result<Midi, MidiError> {
+    read_midi(path, false)
+}
+
+/// Loads midi file and recovers what it can from broken tracks and events.
+/// Only i/o errors and invalid header are fatal, other problems are
+/// reported in `Midi::warnings`.
+pub fn load_midi_lenient(path: &Path) -> Result<Midi, MidiError> {
+    read_midi(path, true)
+}
+
+/// Builds standard midi file from track chunk bodies, chunk lengths are
+/// computed from the bodies.
+#[cfg(test)]
+pub fn smf_bytes(format: u16, division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+    let mut bytes = b"MThd\0\0\0\x06".to_vec();
+    bytes.extend_from_slice(&format.to_be_bytes());
+    bytes.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(&division.to_be_bytes());
+
+    for track in tracks {
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(track);
+    }
+    bytes
+}
+
+/// Loads midi file from bytes in memory. The reader only opens files, so
+/// the bytes go through a temporary file with a unique name.
+#[cfg(test)]
+pub fn load_midi_bytes(bytes: &[u8]) -> Result<Midi, MidiError> {
+    read_midi_bytes(bytes, false)
+}
+
+/// Loads midi file from bytes in memory in lenient mode.
+#[cfg(test)]
+pub fn load_midi_bytes_lenient(bytes: &[u8]) -> Result<Midi, MidiError> {
+    read_midi_bytes(bytes, true)
+}
+
+#[cfg(test)]
+fn read_midi_bytes(bytes: &[u8], lenient: bool) -> Result<Midi, MidiError> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let name = format!("mod_tracker_{}_{}.mid", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+    let path = std::env::temp_dir().join(name);
+    std::fs::write(&path, bytes)?;
+    let midi = read_midi(&path, lenient);
+    std::fs::remove_file(&path)?;
+    midi
+}
+
+fn read_midi(path: &Path, lenient: bool) -> Result<Midi, MidiError> {
+    let name = path.file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut midi = Midi::new(name);
+
+    let mut limits = vec![];
+    let mut pitch_bends = vec![];
+    for scan in scan(&std::fs::read(path)?)? {
+        pitch_bends.push(scan.pitch_bends);
+        limits.push(match scan.error {
+            Some(error) if lenient => {
+                midi.warnings.push(error);
+                Some(scan.events)
+            }
+            Some(error) => return Err(error),
+            None => None,
+        });
+    }
+
+    let mut handler = MidiReader {
+        midi,
+        tick: 0,
+        tempos: vec![],
+        lenient,
+        limits,
+        pitch_bends,
+        bends: 0,
+        events: 0,
+        error: None,
+    };
+
+    let failure = match Reader::new(&mut handler, path) {
+        Ok(mut reader) => reader.read().err().map(read_error),
+        Err(e) => Some(read_error(e)),
+    };
+
+    if let Some(error) = handler.error.take() {
+        return Err(error);
+    }
+
+    if let Some(error) = failure {
+        match error.in_track(handler.track_index()) {
+            e @ MidiError::Io(_) | e @ MidiError::MalformedHeader => return Err(e),
+            e if lenient => handler.midi.warnings.push(e),
+            e => return Err(e),
+        }
+    }
+
+    let tempos = std::mem::take(&mut handler.tempos);
+    handler.midi.apply_tempo_map(&tempos);
+    return Ok(handler.midi);
 }
 
-pub fn load_midi(path: &Path) -> Midi {
-    let mut handler = MidiReader { midi: Midi::new(path.file_name().unwrap().to_str().unwrap().to_owned()) };
-    let mut reader = Reader::new(&mut handler, &path).unwrap();
-    let _ = reader.read();
-    return handler.midi;
+/// Converts error of the `ghakuf` reader. Track related errors are
+/// attributed to the first track and have to be moved with `in_track`.
+fn read_error(error: ReadError) -> MidiError {
+    match error {
+        ReadError::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => MidiError::TruncatedTrack { track: 0 },
+        ReadError::Io(e) => MidiError::Io(e),
+        ReadError::InvalidHeaderTag { .. } | ReadError::InvalidIdentifyCode { .. } => MidiError::MalformedHeader,
+        ReadError::InvalidTrackTag { tag, .. } => MidiError::InvalidChunk { tag },
+        ReadError::UnknownMessageStatus { status, .. } => MidiError::InvalidStatus { track: 0, status },
+        ReadError::NoValidHandler => MidiError::Io(io::Error::other("no valid handler")),
+    }
 }
 
 pub struct Player<'a> {
-    iterators: Vec<Peekable<Iter<'a, Event>>>
+    tracks: Vec<&'a [Event]>,
+    /// Index of the next event of each track.
+    positions: Vec<usize>,
+    /// Start time of each iterated track in microseconds.
+    offsets: Vec<f64>,
+    /// Song time of the end of the played tracks in microseconds.
+    length: f64,
+    /// Playback speed, 2.0 plays the song twice as fast.
+    speed: f64,
+    muted: Vec<bool>,
+    soloed: Vec<bool>,
 }
 
 impl<'a> Player<'a> {
+    /// Creates player that plays all tracks of the file. Patterns of
+    /// format 2 files are played back-to-back.
     pub fn new(midi: &'a Midi) -> Self {
         Player {
-            iterators: midi.tracks.iter().map(|t| t.events.iter().peekable()).collect(),
+            tracks: midi.tracks.iter().map(|t| t.events.as_slice()).collect(),
+            positions: vec![0; midi.tracks.len()],
+            offsets: midi.tracks.iter().map(|t| t.start).collect(),
+            length: midi.total_time,
+            speed: 1.0,
+            muted: vec![false; midi.tracks.len()],
+            soloed: vec![false; midi.tracks.len()],
         }
     }
 
-    pub fn get_events(&mut self, time_micros: f64) -> Vec<&Event> {
+    /// Creates player that plays only one pattern of a format 2 file.
+    pub fn pattern(midi: &'a Midi, index: usize) -> Option<Self> {
+        if index >= midi.patterns() {
+            return None;
+        }
+
+        Some(Player {
+            tracks: vec![midi.tracks[index].events.as_slice()],
+            positions: vec![0],
+            offsets: vec![0.0],
+            length: midi.tracks[index].length,
+            speed: 1.0,
+            muted: vec![false],
+            soloed: vec![false],
+        })
+    }
+
+    #[inline]
+    pub fn length(&self) -> f64 {
+        self.length
+    }
+
+    #[inline]
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+    }
+
+    #[inline]
+    pub fn tracks(&self) -> usize {
+        self.tracks.len()
+    }
+
+    /// Mutes the track, notes which are already playing are finished.
+    pub fn set_mute(&mut self, track: usize, mute: bool) {
+        self.muted[track] = mute;
+    }
+
+    #[inline]
+    pub fn is_muted(&self, track: usize) -> bool {
+        self.muted[track]
+    }
+
+    /// Solo tracks are the only ones played unless they are muted.
+    pub fn set_solo(&mut self, track: usize, solo: bool) {
+        self.soloed[track] = solo;
+    }
+
+    #[inline]
+    pub fn is_soloed(&self, track: usize) -> bool {
+        self.soloed[track]
+    }
+
+    /// Returns playback time of the next event in microseconds.
+    pub fn next_time(&self) -> Option<f64> {
+        let speed = self.speed;
+        self.tracks.iter()
+            .zip(self.positions.iter())
+            .zip(self.offsets.iter())
+            .filter_map(|((events, &pos), offset)| events.get(pos).map(|e| (e.time + offset) / speed))
+            .fold(None, |min: Option<f64>, time| Some(min.map_or(time, |min| min.min(time))))
+    }
+
+    /// Returns events that happen until specified playback time, which is
+    /// scaled by the playback speed to get the song time. Note ons of muted
+    /// tracks are left out.
+    pub fn get_events(&mut self, time_micros: f64) -> Vec<&'a Event> {
         let mut result = vec![];
+        let any_solo = self.soloed.iter().any(|&x| x);
 
-        for x in self.iterators.iter_mut() {
-            let mut should_play = {
-                let mut last = x.peek();
-                last.is_some() && last.unwrap().time <= time_micros
-            };
-
-            while should_play {
-                result.push(x.next().unwrap());
-                should_play = {
-                    let mut last = x.peek();
-                    last.is_some() && last.unwrap().time <= time_micros
-                };
+        for (track, ((events, pos), offset)) in self.tracks.iter().zip(self.positions.iter_mut()).zip(self.offsets.iter()).enumerate() {
+            let time_micros = time_micros * self.speed - offset;
+            let audible = !self.muted[track] && (!any_solo || self.soloed[track]);
+
+            while let Some(event) = events.get(*pos).filter(|e| e.time <= time_micros) {
+                match event.kind {
+                    Kind::NoteOn { .. } if !audible => {}
+                    _ => result.push(event),
+                }
+                *pos += 1;
             }
         }
 
         return result;
     }
+
+    /// Moves to specified playback time, events at that time will be played
+    /// next. Returns all events before it ordered by time (events at the same
+    /// time in order of tracks), so that the channel state can be reconstructed.
+    pub fn seek(&mut self, time_micros: f64) -> Vec<&'a Event> {
+        let mut result = vec![];
+
+        for ((events, pos), &offset) in self.tracks.iter().zip(self.positions.iter_mut()).zip(self.offsets.iter()) {
+            let time_micros = time_micros * self.speed - offset;
+            *pos = events.iter().take_while(|e| e.time < time_micros).count();
+            result.extend(events[..*pos].iter().map(|e| (e.time + offset, e)));
+        }
+
+        /* stable, keeps order of tracks and of events within a track */
+        result.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        return result.into_iter().map(|(_, e)| e).collect();
+    }
 }
 
+/// Registered parameter number that is not selected.
+const RPN_NULL: (u8, u8) = (127, 127);
+const RPN_PITCH_BEND_RANGE: (u8, u8) = (0, 0);
+
+/// Channel 10 (index 9) is reserved for percussion in General MIDI.
+pub const DRUM_CHANNEL: Channel = 9;
+
 pub struct MidiChannel {
     synth: Synth,
+    /// Percussion engine used instead of the synth on drum channels.
+    drums: Option<DrumKit>,
+    /// Bank used by the next program change.
+    bank: Bank,
+    /// Currently selected registered parameter `(msb, lsb)`.
+    rpn: (u8, u8),
+    /// Pitch bend range in semitones set through RPN 0.
+    bend_range: Semitone,
+    pitch_bend: i16,
+    volume: u8,
+    expression: u8,
+    /// Pan position, 0 is hard left, 64 center and 127 hard right.
+    pan: u8,
+    /// Balance of the channel output, 0 is left only, 64 center and 127 right only.
+    balance: u8,
+    /// Mixer gain of the channel applied on top of volume and expression.
+    trim: f64,
+    /// Master tuning added to the pitch bend.
+    tuning: Semitone,
 }
 
 impl MidiChannel {
     pub fn new(sample_rate: f64) -> Self {
         MidiChannel {
             synth: Synth::new(sample_rate),
+            drums: None,
+            bank: Bank::CAPITAL,
+            rpn: RPN_NULL,
+            bend_range: 2.0,
+            pitch_bend: 0,
+            volume: 100,
+            expression: 127,
+            pan: 64,
+            balance: 64,
+            trim: 1.0,
+            tuning: 0.0,
+        }
+    }
+
+    pub fn with_polyphony(sample_rate: f64, polyphony: usize) -> Self {
+        MidiChannel {
+            synth: Synth::with_polyphony(sample_rate, polyphony),
+            ..MidiChannel::new(sample_rate)
+        }
+    }
+
+    pub fn new_drums(sample_rate: f64) -> Self {
+        MidiChannel {
+            drums: Some(DrumKit::new(sample_rate, 32)),
+            ..MidiChannel::new(sample_rate)
+        }
+    }
+
+    pub fn note_on(&mut self, note: Note, velocity: Velocity) {
+        match self.drums {
+            Some(ref mut drums) => drums.note_on(note, velocity),
+            None => self.synth.note_on(note, velocity),
+        }
+    }
+
+    /// Percussion sounds are one-shot and ignore note off.
+    pub fn note_off(&mut self, note: Note) {
+        if self.drums.is_none() {
+            self.synth.note_off(note)
+        }
+    }
+
+    pub fn set_preset(&mut self, preset: &Preset) {
+        if self.drums.is_none() {
+            self.synth.apply_preset(preset)
+        }
+    }
+
+    pub fn all_sound_off(&mut self) {
+        self.synth.all_sound_off();
+        if let Some(ref mut drums) = self.drums {
+            drums.all_sound_off();
+        }
+    }
+
+    pub fn voices(&self) -> (usize, usize) {
+        match self.drums {
+            Some(ref drums) => drums.voices(),
+            None => self.synth.voices(),
+        }
+    }
+
+    /// Fades out one playing voice to make room in the voice budget.
+    pub fn steal_voice(&mut self) -> bool {
+        match self.drums {
+            Some(ref mut drums) => drums.steal_voice(),
+            None => self.synth.steal_voice(),
+        }
+    }
+
+    pub fn control_change(&mut self, control: Control, value: u8) {
+        match control {
+            CC_BANK_SELECT => self.bank.msb = value,
+            CC_BANK_SELECT_LSB => self.bank.lsb = value,
+            CC_MODULATION => self.synth.modulation(value as f64 / 127.0),
+            CC_VOLUME => self.volume = value,
+            CC_BALANCE => self.balance = value,
+            CC_PAN => self.set_pan(value),
+            CC_EXPRESSION => self.expression = value,
+            CC_SUSTAIN => self.synth.sustain_pedal(value >= 64),
+            CC_ALL_SOUND_OFF => self.all_sound_off(),
+            CC_RESET_ALL_CONTROLLERS => self.reset_controllers(),
+            CC_ALL_NOTES_OFF => self.synth.all_notes_off(),
+            CC_RPN_MSB => self.rpn.0 = value,
+            CC_RPN_LSB => self.rpn.1 = value,
+            CC_NRPN_MSB | CC_NRPN_LSB => self.rpn = RPN_NULL, /* data entry goes to nrpn, unsupported */
+            CC_DATA_ENTRY if self.rpn == RPN_PITCH_BEND_RANGE => {
+                self.bend_range = value as f64 + self.bend_range.fract();
+                self.pitch_bend(self.pitch_bend);
+            }
+            CC_DATA_ENTRY_LSB if self.rpn == RPN_PITCH_BEND_RANGE => {
+                self.bend_range = self.bend_range.trunc() + value.min(99) as f64 / 100.0;
+                self.pitch_bend(self.pitch_bend);
+            }
+            _ => {} /* unsupported */
         }
     }
 
-    pub fn next(&mut self) -> f64 {
-        self.synth.next()
+    /// Pans all voices of the channel, voices keep their own offsets.
+    fn set_pan(&mut self, value: u8) {
+        self.pan = value;
+
+        let pan = (value.max(1) - 1) as f64 / 63.0 - 1.0;
+        self.synth.pan(pan);
+        if let Some(ref mut drums) = self.drums {
+            drums.pan(pan);
+        }
+    }
+
+    /// Sets pressure of all notes, drum kits do not respond to aftertouch.
+    pub fn channel_pressure(&mut self, pressure: u8) {
+        if self.drums.is_none() {
+            self.synth.channel_pressure(pressure as f64 / 127.0);
+        }
+    }
+
+    /// Sets pressure of the played note, drum kits do not respond to aftertouch.
+    pub fn key_pressure(&mut self, note: Note, pressure: u8) {
+        if self.drums.is_none() {
+            self.synth.key_pressure(note, pressure as f64 / 127.0);
+        }
+    }
+
+    pub fn pitch_bend(&mut self, value: i16) {
+        self.pitch_bend = value;
+        self.synth.pitch_bend(value as f64 / 8192.0 * self.bend_range + self.tuning);
+    }
+
+    pub fn set_tuning(&mut self, tuning: Semitone) {
+        self.tuning = tuning;
+        self.pitch_bend(self.pitch_bend);
+    }
+
+    /// Returns the channel to its initial state and releases all notes.
+    /// Preset and mixer gain are kept.
+    pub fn reset(&mut self) {
+        self.reset_controllers();
+        self.synth.all_notes_off();
+        self.bank = Bank::CAPITAL;
+        self.bend_range = 2.0;
+        self.volume = 100;
+        self.balance = 64;
+        self.set_pan(64);
+    }
+
+    /// Resets controllers as recommended by RP-015, volume, pan and balance are kept.
+    pub fn reset_controllers(&mut self) {
+        self.expression = 127;
+        self.rpn = RPN_NULL;
+        self.pitch_bend(0);
+        self.synth.channel_pressure(0.0);
+        self.synth.modulation(0.0);
+        self.synth.sustain_pedal(false);
+    }
+
+    /// Channel gain from volume and expression, both follow the
+    /// squared curve recommended by General MIDI.
+    #[inline]
+    fn gain(&self) -> f64 {
+        let volume = self.volume as f64 / 127.0;
+        let expression = self.expression as f64 / 127.0;
+        volume * volume * expression * expression * self.trim
+    }
+
+    pub fn set_trim(&mut self, gain: f64) {
+        self.trim = gain;
+    }
+
+    /// Returns next stereo sample `(left, right)`. Voices are panned by the
+    /// synth, balance only attenuates the opposite side.
+    pub fn next(&mut self) -> (f64, f64) {
+        let (l, r) = match self.drums {
+            Some(ref mut drums) => drums.next(),
+            None => self.synth.next(),
+        };
+
+        let gain = self.gain();
+        let balance = (self.balance.max(1) - 1) as f64 / 63.0 - 1.0;
+
+        (l * gain * (1.0 - balance).min(1.0), r * gain * (1.0 + balance).min(1.0))
     }
 }
 
+/// Default number of voices playing at once across all channels.
+pub const DEFAULT_VOICE_LIMIT: usize = 256;
+
+/// Default priority of a channel in the voice budget.
+pub const DEFAULT_PRIORITY: u8 = 64;
+
 pub struct MidiPlayback {
     channels: Vec<MidiChannel>,
-    preset: Preset,
+    bank: PresetBank,
+    /// Budget of voices playing at once across all channels. Every channel
+    /// still owns its voices, when the budget is used up a note on makes
+    /// one channel steal its own voice.
+    voice_limit: usize,
+    /// Channels with lower priority give up their voices first.
+    priority: [u8; 16],
+    /// Number of voices per channel which are never stolen by other channels.
+    reserved: [usize; 16],
+    /// Semitones added to notes of melodic channels.
+    transpose: i8,
+    /// Semitones added to notes of each channel on top of `transpose`.
+    channel_transpose: [i8; 16],
+    muted: [bool; 16],
+    soloed: [bool; 16],
+    /// Master volume (0.0 - 1.0) set by system exclusive message.
+    master_volume: f64,
+    fine_tuning: Semitone,
+    coarse_tuning: Semitone,
+    /// Note actually played for each sounding note of the file, so that
+    /// note off stops the right note after the transposition changes.
+    sounding: [[Option<Note>; 128]; 16],
+    /// Program changes are ignored, presets were chosen by the user.
+    presets_locked: bool,
+    master: MasterBus,
 }
 
 impl MidiPlayback {
     pub fn new(sample_rate: f64) -> Self {
+        MidiPlayback::with_polyphony(sample_rate, DEFAULT_POLYPHONY)
+    }
+
+    /// Creates playback with specified number of voices of each melodic channel.
+    pub fn with_polyphony(sample_rate: f64, polyphony: usize) -> Self {
+        let bank = PresetBank::gm();
+        let piano = GMInstrument::new(0).unwrap();
+
         MidiPlayback {
-            channels: vec![0; 16].into_iter().map(|x| MidiChannel::new(sample_rate)).collect(),
-            preset: Preset::random(),
+            channels: (0..16)
+                .map(|ch| match ch {
+                    DRUM_CHANNEL => MidiChannel::new_drums(sample_rate),
+                    _ => {
+                        let mut channel = MidiChannel::with_polyphony(sample_rate, polyphony);
+                        channel.set_preset(bank.get(Bank::CAPITAL, piano));
+                        channel
+                    }
+                })
+                .collect(),
+            bank,
+            voice_limit: DEFAULT_VOICE_LIMIT,
+            priority: [DEFAULT_PRIORITY; 16],
+            reserved: [0; 16],
+            transpose: 0,
+            channel_transpose: [0; 16],
+            sounding: [[None; 128]; 16],
+            muted: [false; 16],
+            soloed: [false; 16],
+            master_volume: 1.0,
+            fine_tuning: 0.0,
+            coarse_tuning: 0.0,
+            presets_locked: false,
+            master: MasterBus::new(sample_rate),
+        }
+    }
+
+    /// Transposes all melodic channels, notes already playing are not affected.
+    pub fn set_transpose(&mut self, semitones: i8) {
+        self.transpose = semitones;
+    }
+
+    /// Transposes one channel on top of the global transposition.
+    pub fn set_channel_transpose(&mut self, ch: Channel, semitones: i8) {
+        self.channel_transpose[ch as usize] = semitones;
+    }
+
+    /// Returns note shifted by the transposition, percussion keys select
+    /// instruments and are never transposed.
+    fn transposed(&self, ch: Channel, note: Note) -> Option<Note> {
+        if ch == DRUM_CHANNEL {
+            return Some(note);
+        }
+
+        let note = note as i16 + self.transpose as i16 + self.channel_transpose[ch as usize] as i16;
+        if (0..128).contains(&note) { Some(note as Note) } else { None }
+    }
+
+    /// Chooses which voice of a melodic channel is reused when the channel
+    /// runs out of its voices.
+    pub fn set_steal_policy(&mut self, policy: StealPolicy) {
+        for channel in self.channels.iter_mut() {
+            channel.synth.steal_policy(policy);
         }
     }
 
+    /// Limits the number of voices of all channels playing at once. This is
+    /// a budget on top of the voices of each channel, not a shared pool.
+    pub fn set_voice_limit(&mut self, limit: usize) {
+        self.voice_limit = limit;
+    }
+
+    /// Channels with lower priority give up their voices first when the
+    /// voice limit is reached.
+    pub fn set_priority(&mut self, ch: Channel, priority: u8) {
+        self.priority[ch as usize] = priority;
+    }
+
+    /// Voices of the channel which other channels never take when the voice
+    /// limit is reached.
+    pub fn reserve_voices(&mut self, ch: Channel, voices: usize) {
+        self.reserved[ch as usize] = voices;
+    }
+
     pub fn note_on(&mut self, ch: Channel, note: Note, velocity: Velocity) {
-        self.channels[ch as usize].synth.note_on(note, velocity)
+        /* repeated note on, the previous note may have been transposed differently */
+        if let Some(previous) = self.sounding[ch as usize][note as usize].take() {
+            if Some(previous) != self.transposed(ch, note) {
+                self.channels[ch as usize].note_off(previous);
+            }
+        }
+
+        let played = match self.transposed(ch, note) {
+            Some(played) => played,
+            None => return,
+        };
+
+        /* a channel using all of its own voices reuses one of them, so the
+           number of voices playing does not grow */
+        let (available, used) = self.channels[ch as usize].voices();
+        if used < available && self.voices().1 >= self.voice_limit && !self.free_voice(ch) {
+            return;
+        }
+        self.channels[ch as usize].note_on(played, velocity);
+        self.sounding[ch as usize][note as usize] = Some(played);
+    }
+
+    /// Frees a voice of the budget for a note on specified channel. Channels
+    /// using more voices than they reserve give up voices first, the one with
+    /// the lowest priority and then the most voices. A channel steals from
+    /// channels with higher priority only to fill its reservation, otherwise
+    /// it steals from itself. Returns false when the note has to be dropped.
+    fn free_voice(&mut self, ch: Channel) -> bool {
+        let ch = ch as usize;
+        let used: Vec<usize> = self.channels.iter().map(|c| c.voices().1).collect();
+
+        let victim = (0..16)
+            .filter(|&c| used[c] > self.reserved[c])
+            .min_by_key(|&c| (self.priority[c], std::cmp::Reverse(used[c])));
+
+        let victim = match victim {
+            Some(c) if self.priority[c] <= self.priority[ch] || used[ch] < self.reserved[ch] => c,
+            _ => ch,
+        };
+
+        self.channels[victim].steal_voice()
     }
 
     pub fn note_off(&mut self, ch: Channel, note: Note) {
-        self.channels[ch as usize].synth.note_off(note)
+        if let Some(played) = self.sounding[ch as usize][note as usize].take() {
+            self.channels[ch as usize].note_off(played)
+        }
+    }
+
+    pub fn control_change(&mut self, ch: Channel, control: Control, value: u8) {
+        if control == CC_ALL_NOTES_OFF || control == CC_ALL_SOUND_OFF {
+            self.sounding[ch as usize] = [None; 128];
+        }
+        self.channels[ch as usize].control_change(control, value)
+    }
+
+    pub fn pitch_bend(&mut self, ch: Channel, value: i16) {
+        self.channels[ch as usize].pitch_bend(value)
+    }
+
+    pub fn channel_pressure(&mut self, ch: Channel, pressure: u8) {
+        self.channels[ch as usize].channel_pressure(pressure)
+    }
+
+    /// Sets pressure of the note as it was transposed when played.
+    pub fn key_pressure(&mut self, ch: Channel, note: Note, pressure: u8) {
+        if let Some(played) = self.sounding[ch as usize][note as usize] {
+            self.channels[ch as usize].key_pressure(played, pressure)
+        }
+    }
+
+    /// Applies event of a midi file to the channels.
+    pub fn process(&mut self, kind: &Kind) {
+        match *kind {
+            Kind::NoteOn { ch, note, velocity } => self.note_on(ch, note, velocity),
+            Kind::NoteOff { ch, note } => self.note_off(ch, note),
+            Kind::Instrument { ch, instrument } => self.set_instrument(ch, instrument),
+            Kind::Controller { ch, control, value } => self.control_change(ch, control, value),
+            Kind::PitchBend { ch, value } => self.pitch_bend(ch, value),
+            Kind::ChannelPressure { ch, pressure } => self.channel_pressure(ch, pressure),
+            Kind::KeyPressure { ch, note, pressure } => self.key_pressure(ch, note, pressure),
+            Kind::SysEx { ref data } => self.sys_ex(data),
+        }
+    }
+
+    /// Applies system exclusive message, unsupported messages are ignored.
+    pub fn sys_ex(&mut self, data: &[u8]) {
+        match SysEx::parse(data) {
+            Some(SysEx::GmSystemOn) | Some(SysEx::GmSystemOff) | Some(SysEx::GsReset) | Some(SysEx::XgSystemOn) => self.reset(),
+            Some(SysEx::MasterVolume(volume)) => self.master_volume = volume,
+            Some(SysEx::MasterFineTuning(tuning)) => {
+                self.fine_tuning = tuning;
+                self.apply_tuning();
+            }
+            Some(SysEx::MasterCoarseTuning(tuning)) => {
+                self.coarse_tuning = tuning;
+                self.apply_tuning();
+            }
+            None => {}
+        }
+    }
+
+    /// Percussion keys select instruments and are never tuned.
+    fn apply_tuning(&mut self) {
+        let tuning = self.fine_tuning + self.coarse_tuning;
+        for (ch, channel) in self.channels.iter_mut().enumerate() {
+            if ch != DRUM_CHANNEL as usize {
+                channel.set_tuning(tuning);
+            }
+        }
+    }
+
+    /// Releases all notes and resets all channels to their initial state,
+    /// including programs unless presets were chosen by the user. Master
+    /// volume and tuning are reset too.
+    pub fn reset(&mut self) {
+        let piano = GMInstrument::new(0).unwrap();
+        for ch in 0..16 {
+            self.channels[ch as usize].reset();
+            self.set_instrument(ch, piano);
+        }
+        self.sounding = [[None; 128]; 16];
+
+        self.master_volume = 1.0;
+        self.fine_tuning = 0.0;
+        self.coarse_tuning = 0.0;
+        self.apply_tuning();
+    }
+
+    /// Applies everything except note ons, used to reconstruct channel
+    /// state when playback starts in the middle of a song.
+    pub fn chase(&mut self, kind: &Kind) {
+        match *kind {
+            Kind::NoteOn { .. } | Kind::NoteOff { .. } => {}
+            _ => self.process(kind),
+        }
     }
 
     pub fn set_instrument(&mut self, ch: Channel, instrument: GMInstrument) {
-        self.channels[ch as usize].synth.apply_preset( &Preset {
-            osc1_waveform: Shape::Square,
-            osc2_waveform: Shape::Square,
-            osc1_pitch_mod: 0.0,
-            osc2_pitch_mod: 0.0,
-            osc1_tuning: -12.0,
-            osc2_tuning: 0.0,
-            osc_mix: 0.5,
-            attack: 0.05,
-            decay: 0.1,
-            sustain: 0.8,
-            release: 2.8,
-            filter_mode: Mode::Lowpass,
-            filter_cutoff: 0.05,
-            filter_resonance: 0.2,
-            filter_attack: 0.03,
-            filter_decay: 0.1,
-            filter_sustain: 0.99,
-            filter_release: 0.6,
-            filter_evn_amount: 0.5,
-            lfo_waveform: Shape::Sine,
-            lfo_frequency: 3.0,
-            lfo_filter_mod_amount: 0.0,
-        })
+        if self.presets_locked {
+            return;
+        }
+
+        let channel = &mut self.channels[ch as usize];
+        channel.set_preset(self.bank.get(channel.bank, instrument))
     }
 
+    /// Returns the voice limit and number of voices playing.
     pub fn voices(&self) -> (usize, usize) {
-        let mut available = 0;
-        let mut used = 0;
+        let used = self.channels.iter()
+            .map(|c| c.voices().1)
+            .sum();
 
-        for i in 0..16 {
-            let (a, u) = self.channels[i].synth.voices();
-            available += a;
-            used += u;
-        }
+        return (self.voice_limit, used);
+    }
 
-        return (available, used);
+    /// Silences the channel, its voices keep playing so that notes held
+    /// while muted are heard again when unmuted.
+    pub fn set_mute(&mut self, ch: Channel, mute: bool) {
+        self.muted[ch as usize] = mute;
     }
 
-    pub fn next(&mut self) -> f64 {
-        self.channels.iter_mut()
+    #[inline]
+    pub fn is_muted(&self, ch: Channel) -> bool {
+        self.muted[ch as usize]
+    }
+
+    /// Solo channels are the only ones heard unless they are muted.
+    pub fn set_solo(&mut self, ch: Channel, solo: bool) {
+        self.soloed[ch as usize] = solo;
+    }
+
+    #[inline]
+    pub fn is_soloed(&self, ch: Channel) -> bool {
+        self.soloed[ch as usize]
+    }
+
+    /// Returns next stereo sample `(left, right)` mixed from all channels.
+    pub fn next(&mut self) -> (f64, f64) {
+        let (muted, soloed) = (self.muted, self.soloed);
+        let any_solo = soloed.iter().any(|&x| x);
+
+        let mix = self.channels.iter_mut()
             .enumerate()
-            .filter(|(i, x)| *i != 9)
-            .map(|(i, x)| x.next())
-            .sum()
+            .map(|(ch, x)| (ch, x.next()))
+            .filter(|&(ch, _)| !muted[ch] && (!any_solo || soloed[ch]))
+            .fold((0.0, 0.0), |(l, r), (_, (x, y))| (l + x, r + y));
+
+        /* master volume follows the same curve as channel volume */
+        let volume = self.master_volume * self.master_volume;
+        self.master.next((mix.0 * volume, mix.1 * volume))
+    }
+
+    /// Sets mixer gain of the channel.
+    pub fn set_channel_gain(&mut self, ch: Channel, gain: f64) {
+        self.channels[ch as usize].set_trim(gain)
+    }
+
+    #[inline]
+    pub fn master(&self) -> &MasterBus {
+        &self.master
+    }
+
+    #[inline]
+    pub fn master_mut(&mut self) -> &mut MasterBus {
+        &mut self.master
     }
 
+    /// Uses specified preset on all melodic channels and ignores program changes.
+    pub fn fixed_preset(&mut self, preset: &Preset) {
+        for channel in self.channels.iter_mut() {
+            channel.set_preset(preset)
+        }
+        self.presets_locked = true;
+    }
+
+    /// Uses random presets on all melodic channels and ignores program changes.
     pub fn random_presets(&mut self) {
         for i in 0..16 {
-            self.channels[i].synth.apply_preset(&Preset::random())
+            self.channels[i].set_preset(&Preset::random())
         }
+        self.presets_locked = true;
+    }
+
+    #[inline]
+    pub fn bank(&self) -> &PresetBank {
+        &self.bank
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use crate::midi::{GMInstrument, GMFamily};
+    use crate::midi::{load_midi_bytes, load_midi_bytes_lenient, smf_bytes, MidiPlayback, Player, Kind, MidiError, RPN_NULL, RPN_PITCH_BEND_RANGE};
+    use crate::midi::{CC_VOLUME, CC_PAN, CC_EXPRESSION, CC_SUSTAIN, CC_RESET_ALL_CONTROLLERS};
+    use crate::midi::{CC_RPN_MSB, CC_RPN_LSB, CC_NRPN_MSB, CC_DATA_ENTRY, CC_DATA_ENTRY_LSB};
+    use crate::meta::{BarBeat, TextKind};
+    use crate::midi::{CC_BANK_SELECT, CC_BANK_SELECT_LSB};
+    use crate::bank::{PresetBank, Bank};
+    use crate::synth::{Preset, Synth};
+
+    #[test]
+    fn voice_pool() {
+        let mut playback = MidiPlayback::new(44100.0);
+        playback.set_voice_limit(4);
+        playback.set_priority(1, 100);
+        playback.reserve_voices(2, 1);
+
+        for note in 60..64 {
+            playback.note_on(0, note, 100);
+        }
+        assert_eq!(playback.voices(), (4, 4));
+
+        /* higher priority channel takes voices of channel 0 */
+        playback.note_on(1, 60, 100);
+        playback.note_on(1, 62, 100);
+        assert_eq!(playback.channels[0].voices().1, 2);
+
+        /* reservation is filled even from a higher priority channel */
+        playback.note_on(0, 70, 100);
+        playback.note_on(2, 60, 100);
+        assert_eq!(playback.channels[0].voices().1, 1);
+        assert_eq!(playback.channels[2].voices().1, 1);
+
+        /* equal priority channel gives up its voice, then channel 2 steals from itself */
+        playback.note_on(2, 62, 100);
+        assert_eq!(playback.channels[0].voices().1, 0);
+        playback.note_on(2, 64, 100);
+        assert_eq!(playback.channels[1].voices().1, 2);
+        assert_eq!(playback.channels[2].voices().1, 2);
+        assert_eq!(playback.voices(), (4, 4));
+
+        /* full channel replaces its own voice without taking one from others */
+        let mut playback = MidiPlayback::with_polyphony(44100.0, 2);
+        playback.set_voice_limit(4);
+        playback.set_priority(0, 100);
+        for note in 60..62 {
+            playback.note_on(0, note, 100);
+            playback.note_on(1, note, 100);
+        }
+        playback.note_on(0, 62, 100);
+        assert_eq!(playback.channels[0].voices().1, 2);
+        assert_eq!(playback.channels[1].voices().1, 2);
+    }
+
+    #[test]
+    fn bank_select() {
+        let bank = PresetBank::gm();
+        let piano = GMInstrument::new(0).unwrap();
+
+        /* first samples of a note played with the preset */
+        let expected = |preset: &Preset| {
+            let mut synth = Synth::new(44100.0);
+            synth.apply_preset(preset);
+            synth.note_on(60, 100);
+            (0..1000).map(|_| synth.next()).collect::<Vec<_>>()
+        };
+        let played = |playback: &mut MidiPlayback| {
+            playback.note_on(0, 60, 100);
+            (0..1000).map(|_| playback.channels[0].synth.next()).collect::<Vec<_>>()
+        };
+
+        /* bank select applies with the next program change */
+        let mut playback = MidiPlayback::new(44100.0);
+        playback.process(&Kind::Controller { ch: 0, control: CC_BANK_SELECT, value: 8 });
+        playback.process(&Kind::Controller { ch: 0, control: CC_BANK_SELECT_LSB, value: 3 });
+        assert_eq!(played(&mut playback), expected(bank.get(Bank::CAPITAL, piano)));
+
+        let mut playback = MidiPlayback::new(44100.0);
+        playback.process(&Kind::Controller { ch: 0, control: CC_BANK_SELECT, value: 8 });
+        playback.process(&Kind::Controller { ch: 0, control: CC_BANK_SELECT_LSB, value: 3 });
+        playback.process(&Kind::Instrument { ch: 0, instrument: piano });
+        let variation = expected(bank.get(Bank::new(8, 3), piano));
+        assert_ne!(variation, expected(bank.get(Bank::CAPITAL, piano)));
+        assert_eq!(played(&mut playback), variation);
+    }
+
+    #[test]
+    fn gm_families() {
+        let family = |program| GMInstrument::new(program).unwrap().family();
+        assert_eq!(family(0), GMFamily::Piano);
+        assert_eq!(family(15), GMFamily::ChromaticPercussion);
+        assert_eq!(family(23), GMFamily::Organ);
+        assert_eq!(family(24), GMFamily::Guitar);
+        assert_eq!(family(87), GMFamily::SynthLead);
+        assert_eq!(family(88), GMFamily::SynthPad);
+        assert_eq!(family(127), GMFamily::SoundEffects);
+        assert!(GMInstrument::new(128).is_none());
+    }
+
+    #[test]
+    fn sustain_pedal() {
+        let mut playback = MidiPlayback::new(1000.0);
+        playback.control_change(0, CC_SUSTAIN, 127);
+        playback.note_on(0, 60, 100);
+        playback.note_off(0, 60);
+
+        /* held note keeps sounding until the pedal is released */
+        for _ in 0..5000 {
+            playback.next();
+        }
+        assert_eq!(playback.voices().1, 1);
+
+        playback.control_change(0, CC_SUSTAIN, 0);
+        for _ in 0..5000 {
+            playback.next();
+        }
+        assert_eq!(playback.voices().1, 0);
+    }
+
+    #[test]
+    fn reset_all_controllers() {
+        let mut playback = MidiPlayback::new(44100.0);
+        playback.control_change(0, CC_VOLUME, 50);
+        playback.control_change(0, CC_PAN, 20);
+        playback.control_change(0, CC_EXPRESSION, 30);
+        playback.control_change(0, CC_RPN_MSB, 0);
+        playback.control_change(0, CC_RPN_LSB, 0);
+        playback.pitch_bend(0, 4096);
+
+        /* RP-015 keeps volume and pan */
+        playback.control_change(0, CC_RESET_ALL_CONTROLLERS, 0);
+        let channel = &playback.channels[0];
+        assert_eq!(channel.expression, 127);
+        assert_eq!(channel.pitch_bend, 0);
+        assert_eq!(channel.rpn, RPN_NULL);
+        assert_eq!(channel.volume, 50);
+        assert_eq!(channel.pan, 20);
+    }
+
+    #[test]
+    fn registered_parameters() {
+        let mut playback = MidiPlayback::new(44100.0);
+
+        /* data entry without a selected parameter is ignored */
+        playback.control_change(0, CC_DATA_ENTRY, 12);
+        assert_eq!(playback.channels[0].bend_range, 2.0);
+
+        playback.control_change(0, CC_RPN_MSB, 0);
+        playback.control_change(0, CC_RPN_LSB, 0);
+        assert_eq!(playback.channels[0].rpn, RPN_PITCH_BEND_RANGE);
+        playback.control_change(0, CC_DATA_ENTRY, 12);
+        playback.control_change(0, CC_DATA_ENTRY_LSB, 50);
+        assert_eq!(playback.channels[0].bend_range, 12.5);
+
+        /* null parameter and nrpn deselect the bend range */
+        playback.control_change(0, CC_RPN_MSB, 127);
+        playback.control_change(0, CC_RPN_LSB, 127);
+        playback.control_change(0, CC_DATA_ENTRY, 1);
+        playback.control_change(0, CC_RPN_MSB, 0);
+        playback.control_change(0, CC_RPN_LSB, 0);
+        playback.control_change(0, CC_NRPN_MSB, 0);
+        playback.control_change(0, CC_DATA_ENTRY, 1);
+        assert_eq!(playback.channels[0].rpn, RPN_NULL);
+        assert_eq!(playback.channels[0].bend_range, 12.5);
+    }
+
+    #[test]
+    fn pitch_bend() {
+        /* 14-bit values with non-zero LSB and running status, bends are counted per track */
+        let midi = load_midi_bytes(&smf_bytes(1, 96, &[
+            &[0x00, 0xe0, 0x01, 0x40, 0x00, 0x7f, 0x7f, 0x00, 0xe1, 0x00, 0x00, 0x00, 0xff, 0x2f, 0x00],
+            &[0x00, 0xb0, 0x65, 0x00, 0x00, 0x64, 0x00, 0x00, 0x06, 0x0c, 0x00, 0xe0, 0x00, 0x60, 0x00, 0xff, 0x2f, 0x00],
+        ])).unwrap();
+
+        let bends = |track: usize| -> Vec<i16> {
+            midi.tracks[track].events.iter()
+                .filter_map(|e| match e.kind {
+                    Kind::PitchBend { value, .. } => Some(value),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(bends(0), vec![1, 8191, -8192]);
+        assert_eq!(bends(1), vec![4096]);
+
+        /* RPN 0 sets bend range of 12 semitones */
+        let mut playback = MidiPlayback::new(44100.0);
+        for event in midi.tracks[1].events.iter() {
+            playback.process(&event.kind);
+        }
+        assert_eq!(playback.channels[0].bend_range, 12.0);
+        assert_eq!(playback.channels[0].pitch_bend, 4096);
+    }
+
+    #[test]
+    fn aftertouch_events() {
+        let midi = load_midi_bytes(&smf_bytes(0, 96, &[
+            &[0x00, 0xd1, 0x40, 0x00, 0xa2, 0x3c, 0x7f, 0x00, 0xff, 0x2f, 0x00],
+        ])).unwrap();
+
+        let events = &midi.tracks[0].events;
+        match events[0].kind {
+            Kind::ChannelPressure { ch: 1, pressure: 0x40 } => {}
+            ref kind => panic!("unexpected {:?}", kind),
+        }
+        match events[1].kind {
+            Kind::KeyPressure { ch: 2, note: 0x3c, pressure: 0x7f } => {}
+            ref kind => panic!("unexpected {:?}", kind),
+        }
+
+        /* key pressure reaches the transposed note played for the file note */
+        let output = |pressed: Option<u8>| {
+            let mut playback = MidiPlayback::new(44100.0);
+            playback.set_transpose(2);
+            playback.note_on(0, 60, 100);
+            if let Some(note) = pressed {
+                playback.process(&Kind::KeyPressure { ch: 0, note, pressure: 0x7f });
+            }
+            (0..1000).map(|_| playback.next().0.abs()).sum::<f64>()
+        };
+        assert!(output(Some(60)) > output(None));
+        assert_eq!(output(Some(62)), output(None));
+    }
+
+    #[test]
+    fn transposed_note_off() {
+        let mut playback = MidiPlayback::new(44100.0);
+        playback.set_transpose(2);
+        playback.set_channel_transpose(0, 12);
+        playback.note_on(0, 60, 100);
+        playback.note_on(1, 60, 100);
+        assert_eq!(playback.sounding[0][60], Some(74));
+        assert_eq!(playback.sounding[1][60], Some(62));
+
+        /* note off stops the note that was played, not the newly transposed one */
+        playback.set_transpose(5);
+        playback.note_off(1, 60);
+        assert_eq!(playback.sounding[1][60], None);
+        playback.note_on(1, 60, 100);
+        assert_eq!(playback.sounding[1][60], Some(65));
+
+        /* drums are never transposed and notes out of range are dropped */
+        playback.note_on(9, 36, 100);
+        assert_eq!(playback.sounding[9][36], Some(36));
+        playback.set_transpose(48);
+        playback.note_on(0, 100, 100);
+        assert_eq!(playback.sounding[0][100], None);
+    }
+
+    #[test]
+    fn meta_events() {
+        /* 96 ppqn, SMPTE offset 01:00:02:12.50, 3/4 in Eb major, marker, lyric on beat 2 and marker in bar 2 */
+        let mut track = vec![];
+        track.extend_from_slice(&[0x00, 0xff, 0x54, 0x05, 0x21, 0x00, 0x02, 0x0c, 0x32]);
+        track.extend_from_slice(&[0x00, 0xff, 0x58, 0x04, 0x03, 0x02, 0x18, 0x08]);
+        track.extend_from_slice(&[0x00, 0xff, 0x59, 0x02, 0xfd, 0x00]);
+        track.extend_from_slice(b"\x00\xff\x06\x05Intro");
+        track.extend_from_slice(b"\x60\xff\x05\x03la ");
+        track.extend_from_slice(b"\x81\x40\xff\x06\x05Verse");
+
+        /* key signature with 8 sharps is skipped, strict loading still succeeds */
+        track.extend_from_slice(&[0x00, 0xff, 0x59, 0x02, 0x08, 0x00]);
+        track.extend_from_slice(&[0x00, 0xff, 0x2f, 0x00]);
+
+        let midi = load_midi_bytes(&smf_bytes(0, 96, &[&track])).unwrap();
+
+        assert_eq!(midi.time_signatures[0].value.to_string(), "3/4");
+        assert_eq!(midi.key_signatures.len(), 1);
+        assert_eq!(midi.key_signatures[0].value.to_string(), "Eb major");
+        assert_eq!(midi.warnings.len(), 1);
+
+        /* offset is informational, the song still starts at tick 0 */
+        assert_eq!(midi.smpte_offset.unwrap().to_micros(), 3_602_500_000.0);
+
+        let lyric = midi.texts(TextKind::Lyric).next().unwrap();
+        assert_eq!(lyric.value.text, "la ");
+        assert_eq!(lyric.time, 500_000.0);
+        assert_eq!(midi.bar_beat(lyric.time), Some(BarBeat { bar: 1, beat: 2, tick: 0 }));
+
+        let verse = midi.find_marker("verse").unwrap();
+        assert_eq!(verse.time, 1_500_000.0);
+        assert_eq!(midi.bar_beat(verse.time).unwrap().to_string(), "2:1");
+        assert_eq!(midi.find_marker("in").unwrap().value.text, "Intro");
+        assert!(midi.find_marker("chorus").is_none());
+    }
+
+    #[test]
+    fn system_exclusive() {
+        /* GM System On and master volume at half */
+        let mut track = vec![];
+        track.extend_from_slice(&[0x00, 0xf0, 0x05, 0x7e, 0x7f, 0x09, 0x01, 0xf7]);
+        track.extend_from_slice(&[0x00, 0xf0, 0x07, 0x7f, 0x7f, 0x04, 0x01, 0x00, 0x40, 0xf7]);
+        track.extend_from_slice(&[0x00, 0xff, 0x2f, 0x00]);
+        let midi = load_midi_bytes(&smf_bytes(0, 96, &[&track])).unwrap();
+
+        let events = &midi.tracks[0].events;
+        assert_eq!(events.len(), 2);
+        match events[0].kind {
+            Kind::SysEx { ref data } => assert_eq!(data.as_slice(), &[0x7e, 0x7f, 0x09, 0x01]),
+            ref kind => panic!("unexpected {:?}", kind),
+        }
+
+        let mut playback = MidiPlayback::new(44100.0);
+        playback.control_change(0, CC_VOLUME, 10);
+        playback.note_on(0, 60, 100);
+        for event in events {
+            playback.process(&event.kind);
+        }
+        assert_eq!(playback.channels[0].volume, 100);
+        assert_eq!(playback.sounding[0][60], None);
+        assert_eq!(playback.master_volume, 8192.0 / 16383.0);
+
+        /* master tuning applies to melodic channels only */
+        playback.sys_ex(&[0x7f, 0x7f, 0x04, 0x04, 0x00, 0x42]);
+        playback.sys_ex(&[0x7f, 0x7f, 0x04, 0x03, 0x00, 0x60]);
+        assert_eq!(playback.channels[0].tuning, 2.5);
+        assert_eq!(playback.channels[9].tuning, 0.0);
+
+        /* GS Reset returns tuning to default */
+        playback.sys_ex(&[0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x7f, 0x00, 0x41]);
+        assert_eq!(playback.channels[0].tuning, 0.0);
+        assert_eq!(playback.master_volume, 1.0);
+    }
+
+    #[test]
+    fn split_system_exclusive() {
+        /* GM System On split into F0 packet and F7 continuation, then a note */
+        let mut track = vec![];
+        track.extend_from_slice(&[0x00, 0x90, 0x3c, 0x64]);
+        track.extend_from_slice(&[0x00, 0xf0, 0x03, 0x7e, 0x7f, 0x09]);
+        track.extend_from_slice(&[0x00, 0xf7, 0x02, 0x01, 0xf7]);
+        track.extend_from_slice(&[0x60, 0x80, 0x3c, 0x40]);
+        track.extend_from_slice(&[0x00, 0xff, 0x2f, 0x00]);
+        let bytes = smf_bytes(0, 96, &[&track]);
+
+        match load_midi_bytes(&bytes) {
+            Err(MidiError::SplitSysEx { track: 0 }) => {}
+            other => panic!("unexpected {:?}", other.map(|x| x.tracks[0].events.len())),
+        }
+
+        /* lenient mode keeps the events before the split message */
+        let midi = load_midi_bytes_lenient(&bytes).unwrap();
+        match midi.warnings.as_slice() {
+            [MidiError::SplitSysEx { track: 0 }] => {}
+            warnings => panic!("unexpected {:?}", warnings),
+        }
+        let events = &midi.tracks[0].events;
+        assert_eq!(events.len(), 1);
+        match events[0].kind {
+            Kind::NoteOn { note: 60, .. } => {}
+            ref kind => panic!("unexpected {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn seek_in_time_order() {
+        /* track 0 sets volume on beat 2, track 1 sends GM System On at the start */
+        let midi = load_midi_bytes(&smf_bytes(1, 96, &[
+            &[0x60, 0xb0, 0x07, 0x32, 0x00, 0xff, 0x2f, 0x00],
+            &[0x00, 0xf0, 0x05, 0x7e, 0x7f, 0x09, 0x01, 0xf7, 0x00, 0xff, 0x2f, 0x00],
+        ])).unwrap();
+
+        let mut player = Player::new(&midi);
+        let events = player.seek(1_000_000.0);
+        assert_eq!(events.iter().map(|e| e.tick).collect::<Vec<_>>(), vec![0, 96]);
+
+        let mut playback = MidiPlayback::new(44100.0);
+        for event in events {
+            playback.chase(&event.kind);
+        }
+        assert_eq!(playback.channels[0].volume, 50);
+    }
+
+    #[test]
+    fn format_2_patterns() {
+        /* pattern 0 at 60 bpm, pattern 1 at the default 120 bpm, both with a note on beat 2 */
+        let midi = load_midi_bytes(&smf_bytes(2, 96, &[
+            &[0x00, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40, 0x60, 0x90, 0x3c, 0x64, 0x60, 0x80, 0x3c, 0x00, 0x00, 0xff, 0x2f, 0x00],
+            &[0x60, 0x90, 0x3c, 0x64, 0x60, 0x80, 0x3c, 0x00, 0x00, 0xff, 0x2f, 0x00],
+        ])).unwrap();
+
+        assert_eq!(midi.patterns(), 2);
+        assert_eq!(midi.tracks[0].length, 2_000_000.0);
+        assert_eq!(midi.tracks[1].length, 1_000_000.0);
+        assert_eq!(midi.total_time, 3_000_000.0);
+
+        /* patterns are played back-to-back */
+        let mut player = Player::new(&midi);
+        assert_eq!(player.next_time(), Some(1_000_000.0));
+        assert_eq!(player.get_events(1_000_000.0).len(), 1);
+        assert_eq!(player.next_time(), Some(2_000_000.0));
+        player.get_events(2_000_000.0);
+        assert_eq!(player.next_time(), Some(2_500_000.0));
+
+        /* single pattern starts at its beginning */
+        let player = Player::pattern(&midi, 1).unwrap();
+        assert_eq!(player.next_time(), Some(500_000.0));
+        assert_eq!(player.length(), 1_000_000.0);
+        assert!(Player::pattern(&midi, 2).is_none());
+    }
+
+    #[test]
+    fn format_2_tempo_maps() {
+        /* pattern 0 at 60 bpm in 3/4, pattern 1 at 240 bpm in 4/4, both two beats long */
+        let midi = load_midi_bytes(&smf_bytes(2, 96, &[
+            &[0x00, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40, 0x00, 0xff, 0x58, 0x04, 0x03, 0x02, 0x18, 0x08, 0x81, 0x40, 0xff, 0x2f, 0x00],
+            &[0x00, 0xff, 0x51, 0x03, 0x03, 0xd0, 0x90, 0x81, 0x40, 0xff, 0x2f, 0x00],
+        ])).unwrap();
+
+        /* song tempo map is the one of the first pattern */
+        assert_eq!(midi.tempo_map.changes().len(), 1);
+        assert_eq!(midi.tempo_map.changes()[0].bpm(), 60.0);
+        assert_eq!(midi.tracks[1].tempo_map.as_ref().unwrap().changes()[0].bpm(), 240.0);
+        assert_eq!(midi.tracks[1].start, 2_000_000.0);
+        assert_eq!(midi.total_time, 2_500_000.0);
+
+        let position = |time| midi.bar_beat(time).unwrap().to_string();
+        assert_eq!(position(1_000_000.0), "1:2");
+        assert_eq!(position(2_250_000.0), "1:2");
+        assert_eq!(midi.pattern_bar_beat(1, 250_000.0).unwrap().to_string(), "1:2");
+        assert!(midi.pattern_bar_beat(2, 0.0).is_none());
+    }
+
+    #[test]
+    fn mute_and_solo() {
+        let mut playback = MidiPlayback::new(44100.0);
+        playback.note_on(0, 60, 100);
+
+        playback.set_mute(0, true);
+        assert!((0..1000).all(|_| playback.next() == (0.0, 0.0)));
+
+        /* solo of another channel keeps channel 0 silent after unmuting */
+        playback.set_mute(0, false);
+        playback.set_solo(1, true);
+        assert!((0..1000).all(|_| playback.next() == (0.0, 0.0)));
+
+        playback.set_solo(0, true);
+        assert!((0..1000).any(|_| playback.next() != (0.0, 0.0)));
+        assert_eq!(playback.voices().1, 1);
+    }
+}
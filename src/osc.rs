@@ -12,6 +12,8 @@ pub struct Osc {
     frequency: f64,
     phase: f64,
     pitch_mod: f64,
+    /// Frequency multiplier for vibrato and pitch bend.
+    pitch_ratio: f64,
     sample_rate: f64,
     phase_increment: f64,
     last_output: f64,
@@ -23,6 +25,7 @@ impl Osc {
             shape: Shape::Square,
             frequency: 880.0 * 2.0,
             pitch_mod: 0.0,
+            pitch_ratio: 1.0,
             phase: 0.0,
             sample_rate,
             phase_increment: 0.0,
@@ -36,7 +39,7 @@ impl Osc {
         let modulated_freq = (self.pitch_mod.abs() * 14.0).powf(2.0) - 1.0;
         if self.pitch_mod < 0.0 { let freq = -modulated_freq; }
 
-        let actual_freq = (self.frequency + modulated_freq).clamp(0.0, self.sample_rate / 2.0);
+        let actual_freq = (self.frequency * self.pitch_ratio + modulated_freq).clamp(0.0, self.sample_rate / 2.0);
 
         self.phase_increment = actual_freq * 2.0 * std::f64::consts::PI / self.sample_rate;
     }
@@ -68,6 +71,11 @@ impl Osc {
         self.update_phase_increment();
     }
 
+    pub fn pitch_ratio(&mut self, pitch_ratio: f64) {
+        self.pitch_ratio = pitch_ratio;
+        self.update_phase_increment();
+    }
+
     pub fn frequency(&mut self, frequency: f64) {
         self.frequency = frequency;
         self.update_phase_increment();
@@ -0,0 +1,234 @@
+use cpal::{Device, Format, SampleFormat, SampleRate, SupportedFormat, UnknownTypeOutputBuffer};
+
+/// Triangular probability density dither of one least significant bit,
+/// added before samples are quantized to integers.
+pub struct Dither {
+    state: u32,
+}
+
+impl Dither {
+    pub fn new() -> Self {
+        Dither { state: 0x9e37_79b9 }
+    }
+
+    /// Uniform random number (0.0 - 1.0) from xorshift generator, cheap
+    /// enough to be called from the audio callback.
+    #[inline]
+    fn uniform(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f64 / 4_294_967_296.0
+    }
+
+    /// Returns noise in range (-1.0, 1.0) LSB.
+    #[inline]
+    pub fn next(&mut self) -> f64 {
+        self.uniform() + self.uniform() - 1.0
+    }
+}
+
+/// Sample type of an output buffer.
+pub trait OutputSample: Copy {
+    const SILENCE: Self;
+
+    /// Converts sample (-1.0 - 1.0) to the output type.
+    fn convert(sample: f64, dither: &mut Dither) -> Self;
+}
+
+impl OutputSample for f32 {
+    const SILENCE: Self = 0.0;
+
+    #[inline]
+    fn convert(sample: f64, _: &mut Dither) -> Self {
+        sample as f32
+    }
+}
+
+impl OutputSample for i16 {
+    const SILENCE: Self = 0;
+
+    #[inline]
+    fn convert(sample: f64, dither: &mut Dither) -> Self {
+        (sample * 32767.0 + dither.next()).round().clamp(-32768.0, 32767.0) as i16
+    }
+}
+
+impl OutputSample for u16 {
+    const SILENCE: Self = 32768;
+
+    #[inline]
+    fn convert(sample: f64, dither: &mut Dither) -> Self {
+        (i16::convert(sample, dither) as i32 + 32768) as u16
+    }
+}
+
+/// Writes stereo frames to interleaved output buffers of any sample type and
+/// channel count. Mono devices get the sum of both channels, devices with more
+/// than two channels get left and right in the first two (front) channels and
+/// silence in the rest.
+pub struct Output {
+    channels: usize,
+    dither: Dither,
+}
+
+impl Output {
+    pub fn new(channels: usize) -> Self {
+        Output { channels, dither: Dither::new() }
+    }
+
+    /// Fills the buffer with frames produced by `next`.
+    pub fn fill<F: FnMut() -> (f64, f64)>(&mut self, buffer: UnknownTypeOutputBuffer, next: F) {
+        match buffer {
+            UnknownTypeOutputBuffer::F32(mut buffer) => self.write(&mut buffer, next),
+            UnknownTypeOutputBuffer::I16(mut buffer) => self.write(&mut buffer, next),
+            UnknownTypeOutputBuffer::U16(mut buffer) => self.write(&mut buffer, next),
+        }
+    }
+
+    pub fn write<T: OutputSample, F: FnMut() -> (f64, f64)>(&mut self, buffer: &mut [T], mut next: F) {
+        for frame in buffer.chunks_mut(self.channels.max(1)) {
+            let (l, r) = next();
+            match frame.len() {
+                1 => frame[0] = T::convert((l + r) / 2.0, &mut self.dither),
+                _ => {
+                    frame[0] = T::convert(l, &mut self.dither);
+                    frame[1] = T::convert(r, &mut self.dither);
+                    for x in frame[2..].iter_mut() {
+                        *x = T::SILENCE;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns all output devices, the default device is marked with `true`.
+pub fn devices() -> Vec<(Device, bool)> {
+    let default = cpal::default_output_device().map(|d| d.name());
+    cpal::output_devices()
+        .map(|d| {
+            let is_default = Some(d.name()) == default;
+            (d, is_default)
+        })
+        .collect()
+}
+
+/// Returns supported output formats of the device in the order of preference.
+pub fn supported_formats(device: &Device) -> Result<Vec<SupportedFormat>, String> {
+    let mut formats: Vec<SupportedFormat> = device.supported_output_formats()
+        .map_err(|e| format!("cannot get output formats of {}: {}", device.name(), e))?
+        .collect();
+    formats.sort_by(|a, b| b.cmp_default_heuristics(a));
+    Ok(formats)
+}
+
+/// Finds output device by its index in `devices()`, its exact name or an
+/// unambiguous part of its name. The default device is used without selector.
+pub fn find_device(selector: Option<&str>) -> Result<Device, String> {
+    let selector = match selector {
+        Some(selector) => selector,
+        None => return cpal::default_output_device().ok_or_else(|| "no output device available".to_string()),
+    };
+
+    let mut devices: Vec<Device> = devices().into_iter().map(|(d, _)| d).collect();
+    if devices.is_empty() {
+        return Err("no output device available".to_string());
+    }
+
+    if let Ok(index) = selector.parse::<usize>() {
+        if index >= devices.len() {
+            return Err(format!("no output device with index {}, there are {} devices", index, devices.len()));
+        }
+        return Ok(devices.swap_remove(index));
+    }
+
+    if let Some(index) = devices.iter().position(|d| d.name() == selector) {
+        return Ok(devices.swap_remove(index));
+    }
+
+    let lowercase = selector.to_lowercase();
+    let mut matching: Vec<Device> = devices.into_iter()
+        .filter(|d| d.name().to_lowercase().contains(&lowercase))
+        .collect();
+
+    match matching.len() {
+        0 => Err(format!("no output device named '{}'", selector)),
+        1 => Ok(matching.remove(0)),
+        _ => Err(format!("'{}' matches multiple output devices: {}", selector,
+                         matching.iter().map(|d| d.name()).collect::<Vec<_>>().join(", "))),
+    }
+}
+
+/// Returns format from the supported format of the device with specified
+/// index in `supported_formats()`. The requested sample rate has to be in
+/// the supported range, without one the highest supported rate is used.
+pub fn indexed_format(device: &Device, index: usize, sample_rate: Option<u32>) -> Result<Format, String> {
+    let mut formats = supported_formats(device)?;
+    if index >= formats.len() {
+        return Err(format!("{} has no format with index {}, there are {} formats", device.name(), index, formats.len()));
+    }
+
+    let supported = formats.swap_remove(index);
+    match sample_rate {
+        Some(rate) if supported.min_sample_rate.0 <= rate && rate <= supported.max_sample_rate.0 => Ok(Format {
+            channels: supported.channels,
+            sample_rate: SampleRate(rate),
+            data_type: supported.data_type,
+        }),
+        Some(rate) => Err(format!("format {} of {} does not support sample rate {}", index, device.name(), rate)),
+        None => Ok(supported.with_max_sample_rate()),
+    }
+}
+
+/// Chooses output format of the device. Requested values replace the values
+/// of the default format, the result has to be supported by the device.
+pub fn choose_format(device: &Device, channels: Option<u16>, data_type: Option<SampleFormat>, sample_rate: Option<u32>) -> Result<Format, String> {
+    let mut format = device.default_output_format()
+        .map_err(|e| format!("cannot get output format of {}: {}", device.name(), e))?;
+
+    if channels.is_none() && data_type.is_none() && sample_rate.is_none() {
+        return Ok(format);
+    }
+
+    format.channels = channels.unwrap_or(format.channels);
+    format.data_type = data_type.unwrap_or(format.data_type);
+    format.sample_rate = sample_rate.map(SampleRate).unwrap_or(format.sample_rate);
+
+    let supported = supported_formats(device)?
+        .iter()
+        .any(|f| f.channels == format.channels && f.data_type == format.data_type
+            && f.min_sample_rate <= format.sample_rate && format.sample_rate <= f.max_sample_rate);
+
+    if !supported {
+        return Err(format!("{} does not support {} channels of {:?} at {} Hz",
+                           device.name(), format.channels, format.data_type, format.sample_rate.0));
+    }
+
+    Ok(format)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::output::Output;
+
+    #[test]
+    fn sample_types_and_channels() {
+        let mut output = Output::new(1);
+        let mut buffer = [0i16; 2];
+        output.write(&mut buffer, || (1.0, 0.0));
+        assert!((buffer[0] as i32 - 16384).abs() <= 1);
+
+        /* clipping */
+        let mut output = Output::new(2);
+        let mut buffer = [0u16; 4];
+        output.write(&mut buffer, || (2.0, -2.0));
+        assert_eq!(buffer, [65535, 0, 65535, 0]);
+
+        /* 5.1 gets front left and right only */
+        let mut output = Output::new(6);
+        let mut buffer = [1.0f32; 6];
+        output.write(&mut buffer, || (0.25, -0.25));
+        assert_eq!(buffer, [0.25, -0.25, 0.0, 0.0, 0.0, 0.0]);
+    }
+}
@@ -0,0 +1,328 @@
+use std::io;
+use std::io::{Seek, Write};
+use std::path::Path;
+use crate::midi::{Player, MidiPlayback};
+use crate::wav::{WavWriter, WavSpec, SampleFormat};
+use crate::effects::MasterStats;
+
+/// Longest time rendered after the end of the song while voices release.
+const MAX_RELEASE_TAIL: f64 = 10.0;
+/// Time of the fade out and in when playback is paused and resumed.
+const PAUSE_FADE_TIME: f64 = 0.01;
+
+/// Drives `Player` and `MidiPlayback` frame by frame. Time is derived from
+/// the number of rendered frames instead of the wall clock, so events start
+/// exactly on the frame they belong to regardless of the buffer size.
+pub struct Renderer<'a> {
+    player: Player<'a>,
+    playback: MidiPlayback,
+    sample_rate: f64,
+    /// Number of frames rendered while playing.
+    frame: u64,
+    /// Playback time of the frame `start_frame` in microseconds.
+    start: f64,
+    start_frame: u64,
+    /// Playback time of the next event of the player.
+    next_event: Option<f64>,
+    paused: bool,
+    /// Output gain fading between paused (0.0) and playing (1.0).
+    fade: f64,
+    fade_step: f64,
+    /// Section `(a, b)` in song time which is played repeatedly.
+    loop_range: Option<(f64, f64)>,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(player: Player<'a>, playback: MidiPlayback, sample_rate: f64) -> Self {
+        let next_event = player.next_time();
+        Renderer {
+            player,
+            playback,
+            sample_rate,
+            frame: 0,
+            start: 0.0,
+            start_frame: 0,
+            next_event,
+            paused: false,
+            fade: 1.0,
+            fade_step: 1.0 / (PAUSE_FADE_TIME * sample_rate),
+            loop_range: None,
+        }
+    }
+
+    #[inline]
+    pub fn playback(&self) -> &MidiPlayback {
+        &self.playback
+    }
+
+    #[inline]
+    pub fn playback_mut(&mut self) -> &mut MidiPlayback {
+        &mut self.playback
+    }
+
+    #[inline]
+    pub fn player_mut(&mut self) -> &mut Player<'a> {
+        &mut self.player
+    }
+
+    /// Changes playback speed, the song continues from the current position.
+    pub fn set_speed(&mut self, speed: f64) {
+        let song_time = self.song_time();
+        self.player.set_speed(speed);
+
+        self.start = song_time / speed;
+        self.start_frame = self.frame;
+        self.next_event = self.player.next_time();
+    }
+
+    /// Continues playback at specified song time. Sounding notes are
+    /// released and the channel state is reconstructed from all events
+    /// before that time except notes.
+    pub fn seek(&mut self, song_micros: f64) {
+        let start = song_micros.max(0.0) / self.player.speed();
+
+        self.playback.reset();
+        for event in self.player.seek(start) {
+            self.playback.chase(&event.kind);
+        }
+
+        self.start = start;
+        self.start_frame = self.frame;
+        self.next_event = self.player.next_time();
+    }
+
+    /// Playback time of the next frame in microseconds.
+    #[inline]
+    pub fn time(&self) -> f64 {
+        self.start + (self.frame - self.start_frame) as f64 * 1_000_000.0 / self.sample_rate
+    }
+
+    /// Song time of the next frame in microseconds.
+    #[inline]
+    pub fn song_time(&self) -> f64 {
+        self.time() * self.player.speed()
+    }
+
+    /// Stops the song time, voices keep their state and the output fades out.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Plays section between song times `a` and `b` repeatedly, `b` has to be
+    /// after `a`. Playback jumps to `a` whenever it reaches `b`.
+    pub fn set_loop(&mut self, range: Option<(f64, f64)>) {
+        debug_assert!(match range {
+            Some((a, b)) => a < b,
+            None => true,
+        });
+        self.loop_range = range;
+    }
+
+    /// Returns next stereo frame `(left, right)`.
+    pub fn next(&mut self) -> (f64, f64) {
+        if self.paused {
+            if self.fade <= 0.0 {
+                return (0.0, 0.0);
+            }
+            self.fade = (self.fade - self.fade_step).max(0.0);
+        } else {
+            if let Some((a, b)) = self.loop_range {
+                if self.song_time() >= b {
+                    self.seek(a);
+                }
+            }
+
+            let now = self.time();
+            if self.next_event.filter(|&time| time <= now).is_some() {
+                for event in self.player.get_events(now) {
+                    self.playback.process(&event.kind);
+                }
+                self.next_event = self.player.next_time();
+            }
+
+            self.frame += 1;
+            self.fade = (self.fade + self.fade_step).min(1.0);
+        }
+
+        let (l, r) = self.playback.next();
+        (l * self.fade, r * self.fade)
+    }
+}
+
+/// Summary of a finished render.
+#[derive(Debug, Copy, Clone)]
+pub struct RenderStats {
+    /// Length of the rendered audio in seconds.
+    pub duration: f64,
+    /// Rendering stopped at the 4 GiB limit of WAV files before the end.
+    pub truncated: bool,
+    pub master: MasterStats,
+}
+
+/// Renders the rest of the song into WAV file, including the release tail
+/// after the last event.
+pub fn render_wav(renderer: Renderer, path: &Path, channels: u16, format: SampleFormat) -> io::Result<RenderStats> {
+    if channels != 1 && channels != 2 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "only mono and stereo output is supported"));
+    }
+
+    let sample_rate = renderer.sample_rate as u32;
+    let wav = WavWriter::create(path, WavSpec { channels, sample_rate, format })?;
+    write_wav(renderer, wav).map(|(_, stats)| stats)
+}
+
+/// Renders the rest of the song into the writer and finalizes it. When the
+/// file gets full the audio is cut off and the file stays valid.
+fn write_wav<W: Write + Seek>(mut renderer: Renderer, mut wav: WavWriter<W>) -> io::Result<(W, RenderStats)> {
+    let sample_rate = renderer.sample_rate;
+    let end = renderer.player.length() / renderer.player.speed();
+    let song_frames = ((end - renderer.time()).max(0.0) * sample_rate / 1_000_000.0).ceil() as u64;
+    let latency = renderer.playback.master().latency() as u64;
+    let mut end_frame = song_frames + (MAX_RELEASE_TAIL * sample_rate) as u64 + latency;
+    let mut truncated = false;
+
+    while renderer.frame < end_frame {
+        if wav.is_full() {
+            truncated = true;
+            break;
+        }
+
+
+        /* stop once all voices are released and the limiter delay is flushed */
+        if renderer.frame > song_frames && renderer.frame % 1024 == 0 && renderer.playback.voices().1 == 0 {
+            end_frame = end_frame.min(renderer.frame + latency);
+        }
+
+        let (l, r) = renderer.next();
+        match wav.spec().channels {
+            1 => wav.write_frame(&[(l + r) / 2.0])?,
+            _ => wav.write_frame(&[l, r])?,
+        }
+    }
+
+    let stats = RenderStats {
+        duration: renderer.frame as f64 / sample_rate,
+        truncated,
+        master: renderer.playback.master().stats(),
+    };
+    Ok((wav.finalize()?, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::midi::{load_midi_bytes, smf_bytes, Midi, Player, MidiPlayback};
+    use crate::render::{Renderer, write_wav};
+    use crate::wav::{WavWriter, WavSpec, SampleFormat};
+    use std::io::Cursor;
+
+    /// 96 ppqn at 120 bpm, note on after one beat (0.5 s) and note off after another.
+    fn one_note() -> Midi {
+        load_midi_bytes(&smf_bytes(0, 96, &[
+            &[0x60, 0x90, 0x3c, 0x7f, 0x60, 0x80, 0x3c, 0x00, 0x00, 0xff, 0x2f, 0x00],
+        ])).unwrap()
+    }
+
+    #[test]
+    fn sample_accurate_events() {
+        let midi = one_note();
+
+        let mut renderer = Renderer::new(Player::new(&midi), MidiPlayback::new(44100.0), 44100.0);
+        for _ in 0..22050 {
+            assert_eq!(renderer.next(), (0.0, 0.0));
+        }
+        assert_eq!(renderer.playback().voices().1, 0);
+        renderer.next();
+        assert_eq!(renderer.playback().voices().1, 1);
+    }
+
+    #[test]
+    fn muted_track() {
+        let midi = one_note();
+        let mut player = Player::new(&midi);
+        player.set_mute(0, true);
+
+        let mut renderer = Renderer::new(player, MidiPlayback::new(1000.0), 1000.0);
+        for _ in 0..1000 {
+            renderer.next();
+        }
+        assert_eq!(renderer.playback().voices().1, 0);
+
+        renderer.player_mut().set_mute(0, false);
+        renderer.seek(500_000.0);
+        renderer.next();
+        assert_eq!(renderer.playback().voices().1, 1);
+    }
+
+    #[test]
+    fn seek_pause_and_loop() {
+        let midi = one_note();
+        let mut renderer = Renderer::new(Player::new(&midi), MidiPlayback::new(1000.0), 1000.0);
+
+        /* events at the seek position are played, earlier notes are not */
+        renderer.seek(500_000.0);
+        renderer.next();
+        assert_eq!(renderer.playback().voices().1, 1);
+        renderer.seek(750_000.0);
+        for _ in 0..1000 {
+            renderer.next();
+        }
+        assert_eq!(renderer.playback().voices().1, 0);
+
+        /* paused song time does not move and the output fades to silence */
+        renderer.seek(0.0);
+        renderer.pause();
+        for _ in 0..100 {
+            renderer.next();
+        }
+        assert_eq!(renderer.song_time(), 0.0);
+        assert_eq!(renderer.next(), (0.0, 0.0));
+        renderer.resume();
+
+        /* playback jumps back to the loop start */
+        renderer.set_loop(Some((400_000.0, 600_000.0)));
+        for _ in 0..1000 {
+            renderer.next();
+            assert!(renderer.song_time() <= 600_000.0);
+        }
+        assert!(renderer.song_time() >= 400_000.0);
+
+        /* changing speed keeps the position in the song */
+        renderer.set_loop(None);
+        let position = renderer.song_time();
+        renderer.set_speed(0.5);
+        assert!((renderer.song_time() - position).abs() < 1e-6);
+        for _ in 0..10 {
+            renderer.next();
+        }
+        assert!((renderer.song_time() - position - 5_000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn size_limit() {
+        /* only 100 frames fit into the file before the 4 GiB limit */
+        let midi = one_note();
+        let renderer = Renderer::new(Player::new(&midi), MidiPlayback::new(1000.0), 1000.0);
+        let spec = WavSpec { channels: 2, sample_rate: 1000, format: SampleFormat::Pcm16 };
+        let mut wav = WavWriter::new(Cursor::new(vec![]), spec).unwrap();
+        let frames = (u32::MAX as u64 - 36) / 4;
+        wav.skip_frames(frames - 100);
+
+        let (writer, stats) = write_wav(renderer, wav).unwrap();
+        assert!(stats.truncated);
+        assert_eq!(stats.duration, 0.1);
+
+        /* header is finalized with the size of all frames */
+        let bytes = writer.into_inner();
+        assert_eq!(bytes.len(), 44 + 400);
+        assert_eq!(&bytes[40..44], &((frames * 4) as u32).to_le_bytes());
+    }
+}
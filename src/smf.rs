@@ -0,0 +1,255 @@
+use crate::midi::MidiError;
+
+/// Structure of one track chunk as the `ghakuf` reader is going to see it.
+#[derive(Debug)]
+pub struct TrackScan {
+    /// Number of complete events (meta, midi and sysex) inside the chunk.
+    pub events: usize,
+    /// Problem found right after the last complete event.
+    pub error: Option<MidiError>,
+    /// Raw 14-bit values of pitch bend events in the order they appear.
+    pub pitch_bends: Vec<u16>,
+}
+
+/// Walks the chunks and events of a standard midi file the same way `ghakuf`
+/// does, without decoding them. `ghakuf` panics on subtraction overflow (or
+/// reads the following chunk as events) when an event overruns its chunk, so
+/// such tracks have to be found before the file is handed to it.
+///
+/// Pitch bend values are collected here too, `ghakuf` 0.5 decodes them as
+/// `(msb << 8 & lsb) - 8192` which is always -8192.
+///
+/// `ghakuf` drops the status of an F7 event that follows an F0 event and
+/// reads its length and data as new events, so system exclusive messages
+/// split into F0 and F7 packets end the readable part of the track.
+pub fn scan(bytes: &[u8]) -> Result<Vec<TrackScan>, MidiError> {
+    if bytes.len() < 14 || &bytes[0..4] != b"MThd" || read_u32(bytes, 4) != Some(6) {
+        return Err(MidiError::MalformedHeader);
+    }
+
+    let mut tracks = vec![];
+    let mut pos = 14;
+
+    while pos + 4 <= bytes.len() && &bytes[pos..pos + 4] == b"MTrk" {
+        let track = tracks.len();
+        let mut remaining = match read_u32(bytes, pos + 4) {
+            Some(len) => len as i64,
+            None => {
+                tracks.push(TrackScan { events: 0, error: Some(MidiError::TruncatedTrack { track }), pitch_bends: vec![] });
+                break;
+            }
+        };
+
+        let mut scan = TrackScan { events: 0, error: None, pitch_bends: vec![] };
+        let mut cursor = Cursor { bytes, pos: pos + 8 };
+        let mut running_status = 0u8;
+
+        while remaining > 0 {
+            let start = cursor.pos;
+            match next_event(&mut cursor, &mut running_status) {
+                Ok(step) if step.consumed <= remaining => {
+                    remaining -= step.consumed;
+                    if step.is_event {
+                        scan.events += 1;
+                    }
+                    scan.pitch_bends.extend(step.pitch_bend);
+                }
+                Ok(_) | Err(ScanError::Truncated) => {
+                    cursor.pos = start;
+                    scan.error = Some(MidiError::TruncatedTrack { track });
+                }
+                Err(ScanError::InvalidStatus(status)) => {
+                    cursor.pos = start;
+                    scan.error = Some(MidiError::InvalidStatus { track, status });
+                }
+                Err(ScanError::SplitSysEx) => {
+                    cursor.pos = start;
+                    scan.error = Some(MidiError::SplitSysEx { track });
+                }
+            }
+
+            if scan.error.is_some() {
+                break;
+            }
+        }
+
+        // reader skips the rest of the declared chunk length from here
+        pos = cursor.pos + remaining as usize;
+        tracks.push(scan);
+    }
+
+    Ok(tracks)
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.bytes.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        if self.pos + len > self.bytes.len() {
+            return None;
+        }
+        self.pos += len;
+        Some(())
+    }
+
+    /// Reads variable length quantity, returns its value and the length
+    /// `ghakuf` accounts for it (length of the canonical encoding).
+    fn vlq(&mut self) -> Option<(u32, i64)> {
+        let mut val = 0u32;
+        loop {
+            let byte = self.byte()?;
+            val = val << 7 | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 || val > 0x1f_ffff {
+                break;
+            }
+        }
+
+        let mut len = 1;
+        let mut rest = val >> 7;
+        while rest > 0 {
+            len += 1;
+            rest >>= 7;
+        }
+        Some((val, len))
+    }
+}
+
+struct Step {
+    /// Number of bytes `ghakuf` subtracts from the chunk length.
+    consumed: i64,
+    /// Whether the event is reported to the handler.
+    is_event: bool,
+    pitch_bend: Option<u16>,
+}
+
+impl Step {
+    fn event(consumed: i64) -> Self {
+        Step { consumed, is_event: true, pitch_bend: None }
+    }
+}
+
+enum ScanError {
+    /// File ends in the middle of the event.
+    Truncated,
+    InvalidStatus(u8),
+    /// F7 packet continues the previous F0 event.
+    SplitSysEx,
+}
+
+/// Skips one event.
+fn next_event(cursor: &mut Cursor, running_status: &mut u8) -> Result<Step, ScanError> {
+    let (_, delta_len) = cursor.vlq().ok_or(ScanError::Truncated)?;
+    let mut consumed = delta_len;
+
+    let mut status = cursor.byte().ok_or(ScanError::Truncated)?;
+    if status < 0x80 {
+        status = *running_status;
+        cursor.pos -= 1;
+    } else {
+        consumed += 1;
+    }
+
+    match status {
+        0xff => {
+            cursor.byte().ok_or(ScanError::Truncated)?;
+            let (len, len_len) = cursor.vlq().ok_or(ScanError::Truncated)?;
+            cursor.skip(len as usize).ok_or(ScanError::Truncated)?;
+            Ok(Step::event(consumed + 1 + len_len + len as i64))
+        }
+        0x80..=0xef => {
+            *running_status = status;
+            match status & 0xf0 {
+                0xc0 | 0xd0 => {
+                    cursor.skip(1).ok_or(ScanError::Truncated)?;
+                    Ok(Step::event(consumed + 1))
+                }
+                0xe0 => {
+                    let lsb = cursor.byte().ok_or(ScanError::Truncated)?;
+                    let msb = cursor.byte().ok_or(ScanError::Truncated)?;
+                    Ok(Step {
+                        pitch_bend: Some(((msb & 0x7f) as u16) << 7 | (lsb & 0x7f) as u16),
+                        ..Step::event(consumed + 2)
+                    })
+                }
+                _ => {
+                    cursor.skip(2).ok_or(ScanError::Truncated)?;
+                    Ok(Step::event(consumed + 2))
+                }
+            }
+        }
+        0xf7 if *running_status == 0xf0 => Err(ScanError::SplitSysEx),
+        0xf0 | 0xf7 => {
+            let (len, len_len) = cursor.vlq().ok_or(ScanError::Truncated)?;
+            cursor.skip(len as usize).ok_or(ScanError::Truncated)?;
+            if status == 0xf0 {
+                *running_status = 0xf0;
+            }
+            Ok(Step::event(consumed + len_len + len as i64))
+        }
+        _ => Err(ScanError::InvalidStatus(status)),
+    }
+}
+
+fn read_u32(bytes: &[u8], pos: usize) -> Option<u32> {
+    let b = bytes.get(pos..pos + 4)?;
+    Some((b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::smf::scan;
+    use crate::midi::MidiError;
+
+    fn file(track: &[u8], declared_len: u32) -> Vec<u8> {
+        let mut bytes = b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk".to_vec();
+        bytes.extend_from_slice(&declared_len.to_be_bytes());
+        bytes.extend_from_slice(track);
+        bytes
+    }
+
+    #[test]
+    fn complete_track() {
+        /* note on, running status note on, pitch bend, end of track */
+        let track = [0x00, 0x90, 0x3c, 0x40, 0x10, 0x3c, 0x00, 0x00, 0xe0, 0x00, 0x60, 0x00, 0xff, 0x2f, 0x00];
+        let tracks = scan(&file(&track, track.len() as u32)).unwrap();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].events, 4);
+        assert!(tracks[0].error.is_none());
+        assert_eq!(tracks[0].pitch_bends, vec![0x3000]);
+    }
+
+    #[test]
+    fn overrun_and_truncation() {
+        let track = [0x00, 0x90, 0x3c, 0x40, 0x10, 0x3c, 0x00, 0x00, 0xff, 0x2f, 0x00];
+
+        /* declared length ends in the middle of the second event */
+        let tracks = scan(&file(&track, 6)).unwrap();
+        assert_eq!(tracks[0].events, 1);
+        match tracks[0].error {
+            Some(MidiError::TruncatedTrack { track: 0 }) => {}
+            ref e => panic!("unexpected {:?}", e),
+        }
+
+        /* file ends before the declared length */
+        let tracks = scan(&file(&track[..9], track.len() as u32)).unwrap();
+        assert_eq!(tracks[0].events, 2);
+        assert!(tracks[0].error.is_some());
+    }
+
+    #[test]
+    fn malformed_header() {
+        match scan(b"RIFF\0\0\0\x06\0\0\0\x01\0\x60") {
+            Err(MidiError::MalformedHeader) => {}
+            r => panic!("unexpected {:?}", r.map(|x| x.len())),
+        }
+    }
+}
@@ -3,7 +3,9 @@ use crate::osc::{Osc, Shape};
 use crate::midi::note2freq;
 use crate::env::Envelope;
 use crate::filter::{Mode, Filter};
+use crate::math::pan_gains;
 use rand::Rng;
+use std::cmp::Ordering;
 
 #[derive(Copy, Clone)]
 pub struct Voice {
@@ -19,10 +21,60 @@ pub struct Voice {
     pub note: u8,
     pub filter_envelope_amount: f64,
     pub is_active: bool,
+    /// Note was released while the sustain pedal was held.
+    pub sustained: bool,
+    /// Polyphonic aftertouch of this voice (0.0 - 1.0).
+    pub pressure: f64,
+    /// Sequence number of the note on that started this voice.
+    pub age: u64,
+    /// Pan offset of this voice from key tracking and voice spread.
+    pub pan: f64,
+    /// Output gains and the pan position they were computed for.
+    pan_gains: (f64, f64),
+    gains_pan: f64,
 }
 
 pub type Semitone = f64;
 
+/// Modulation shared by all voices of a synth, computed once per sample.
+#[derive(Copy, Clone)]
+pub struct Modulation {
+    pub lfo_value: f64,
+    pub lfo_filter_amount: f64,
+    /// Frequency multiplier from pitch bend and modulation wheel vibrato.
+    pub pitch_ratio: f64,
+    /// Channel pressure (0.0 - 1.0), voices use the larger of it and their own pressure.
+    pub pressure: f64,
+    pub pressure_cutoff: f64,
+    pub pressure_amplitude: f64,
+    /// Vibrato depth in semitones at full pressure.
+    pub pressure_pitch_mod: Semitone,
+    /// Pan position of the synth (-1.0 - 1.0), voices add their own offset.
+    pub pan: f64,
+}
+
+/// Vibrato depth with the modulation wheel fully up.
+const MODULATION_DEPTH: Semitone = 0.5;
+
+/// Length of the fade out of a stolen voice in seconds.
+const STEAL_FADE_TIME: f64 = 0.005;
+
+/// Default number of voices of one synth.
+pub const DEFAULT_POLYPHONY: usize = 128;
+
+/// Chooses which voice is reused when a note starts and all voices are active.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StealPolicy {
+    /// Voice started the longest time ago.
+    Oldest,
+    /// Voice with the lowest envelope level.
+    Quietest,
+    /// Voice started with the lowest velocity, the oldest one of them.
+    LowestVelocity,
+    /// Voice already playing the same note, otherwise the oldest one.
+    SameNote,
+}
+
 impl Voice {
     fn new(sample_rate: f64) -> Self {
         Voice {
@@ -38,26 +90,62 @@ impl Voice {
             velocity: 1.0,
             note: 0,
             is_active: false,
+            sustained: false,
+            pressure: 0.0,
+            age: 0,
+            pan: 0.0,
+            pan_gains: (1.0, 1.0),
+            gains_pan: 0.0,
         }
     }
 
-    pub fn next(&mut self, lfo_value: f64, lfo_filter_amount: f64) -> f64 {
+    /// Current output level before the filter.
+    #[inline]
+    fn level(&self) -> f64 {
+        self.env.level() * self.velocity
+    }
+
+    /// Returns next stereo sample `(left, right)`.
+    pub fn next(&mut self, m: &Modulation) -> (f64, f64) {
         if self.env.state() == Off { self.is_active = false; }
 
+        let lfo_value = m.lfo_value;
+        let pressure = self.pressure.max(m.pressure);
+
         let osc1 = self.osc1.next();
         let osc2 = self.osc2.next();
         let mix = ((1.0 - self.osc_mix) * osc1) + (self.osc_mix * osc2);
 
-        self.filter.cutoff_mod(self.filter_env.next() * self.filter_envelope_amount + lfo_value * lfo_filter_amount);
+        self.filter.cutoff_mod(self.filter_env.next() * self.filter_envelope_amount
+            + lfo_value * m.lfo_filter_amount
+            + pressure * m.pressure_cutoff);
+
+        let mut pitch_ratio = m.pitch_ratio;
+        if pressure > 0.0 && m.pressure_pitch_mod != 0.0 {
+            pitch_ratio *= 2.0f64.powf(lfo_value * pressure * m.pressure_pitch_mod / 12.0);
+        }
 
+        self.osc1.pitch_ratio(pitch_ratio);
+        self.osc2.pitch_ratio(pitch_ratio);
         self.osc1.pitch_mod(lfo_value * self.osc1_pitch_mod);
         self.osc2.pitch_mod(lfo_value * self.osc2_pitch_mod);
 
-        return self.filter.next(mix * self.env.next() * self.velocity);
+        let amplitude = 1.0 + pressure * m.pressure_amplitude;
+        let v = self.filter.next(mix * self.env.next() * self.velocity * amplitude);
+
+        let pan = (m.pan + self.pan).clamp(-1.0, 1.0);
+        if pan != self.gains_pan {
+            self.pan_gains = pan_gains(pan);
+            self.gains_pan = pan;
+        }
+
+        return (v * self.pan_gains.0, v * self.pan_gains.1);
     }
 
     pub fn reset(&mut self) {
         self.velocity = 0.0;
+        self.sustained = false;
+        self.pressure = 0.0;
         self.osc1.reset();
         self.osc2.reset();
         self.env.reset();
@@ -67,61 +155,215 @@ impl Voice {
 }
 
 pub struct Voices {
+    /// Allocated voices, voices that finished playing are dropped.
     voices: Vec<Voice>,
+    /// Voice with the current preset applied, new voices are copied from it.
+    template: Voice,
+    /// Maximum number of voices playing at once.
+    polyphony: usize,
+    /// Copies of stolen voices with their current gain.
+    fading: Vec<(Voice, f64)>,
+    /// Gain decrement of fading voices per sample.
+    fade_step: f64,
+    steal_policy: StealPolicy,
+    /// Number of note ons so far, used as voice age.
+    note_count: u64,
     lfo: Osc,
     lfo_filter_amount: f64,
     osc1_tuning: Semitone,
     osc2_tuning: Semitone,
+    /// Modulation wheel position (0.0 - 1.0) scaling LFO vibrato.
+    modulation: f64,
+    pitch_bend: Semitone,
+    sustain_pedal: bool,
+    channel_pressure: f64,
+    pressure_cutoff: f64,
+    pressure_amplitude: f64,
+    pressure_pitch_mod: Semitone,
+    pan: f64,
+    /// Pan offset of the highest and lowest note (0.0 - 1.0).
+    key_pan_spread: f64,
+    /// Pan offset of consecutive notes alternating left and right (0.0 - 1.0).
+    voice_spread: f64,
+    /// Side of the next note for voice spread (-1.0 or 1.0).
+    spread_side: f64,
 }
 
 impl Voices {
     fn new(sample_rate: f64, polyphony: usize) -> Self {
         Voices {
-            voices: vec![Voice::new(sample_rate); polyphony],
+            voices: Vec::with_capacity(polyphony),
+            template: Voice::new(sample_rate),
+            polyphony,
+            fading: vec![],
+            fade_step: 1.0 / (STEAL_FADE_TIME * sample_rate),
+            steal_policy: StealPolicy::Oldest,
+            note_count: 0,
             lfo: Osc::new(sample_rate),
             lfo_filter_amount: 0.0,
             osc1_tuning: 0.0,
             osc2_tuning: 0.0,
+            modulation: 0.0,
+            pitch_bend: 0.0,
+            sustain_pedal: false,
+            channel_pressure: 0.0,
+            pressure_cutoff: 0.0,
+            pressure_amplitude: 0.0,
+            pressure_pitch_mod: 0.0,
+            pan: 0.0,
+            key_pan_spread: 0.0,
+            voice_spread: 0.0,
+            spread_side: 1.0,
         }
     }
 
     pub fn note_on(&mut self, note: u8, velocity: u8) {
+        let idx = match self.voices.iter().position(|v| !v.is_active) {
+            Some(idx) => idx,
+            None if self.voices.len() < self.polyphony => {
+                self.voices.push(self.template);
+                self.voices.len() - 1
+            }
+            None => match self.steal(Some(note)) {
+                Some(idx) => idx,
+                None => return,
+            }
+        };
+
+        self.note_count += 1;
+
+        let v = &mut self.voices[idx];
+        v.reset();
+        v.is_active = true;
+        v.note = note;
+        v.age = self.note_count;
+        v.pan = (note as f64 - 64.0) / 64.0 * self.key_pan_spread + self.spread_side * self.voice_spread;
+        self.spread_side = -self.spread_side;
+        v.velocity = velocity as f64 / 127.0;
+        v.osc1.frequency(note2freq(note as f64 + self.osc1_tuning));
+        v.osc2.frequency(note2freq(note as f64 + self.osc2_tuning));
+        v.env.enter_state(Attack);
+        v.filter_env.enter_state(Attack);
+    }
+
+    /// Fades out voice chosen by the steal policy and returns its index.
+    fn steal(&mut self, note: Option<u8>) -> Option<usize> {
+        let idx = self.victim(note)?;
+        self.fading.push((self.voices[idx], 1.0));
+        self.voices[idx].is_active = false;
+        Some(idx)
+    }
+
+    /// Returns index of the active voice to steal according to the steal policy.
+    fn victim(&self, note: Option<u8>) -> Option<usize> {
+        let voices = self.voices.iter().enumerate().filter(|(_, v)| v.is_active);
+        let oldest = voices.clone().min_by_key(|(_, v)| v.age);
+
+        let victim = match self.steal_policy {
+            StealPolicy::Oldest => oldest,
+            StealPolicy::Quietest => voices
+                .min_by(|(_, a), (_, b)| a.level().partial_cmp(&b.level()).unwrap_or(Ordering::Equal)),
+            StealPolicy::LowestVelocity => voices
+                .min_by(|(_, a), (_, b)| a.velocity.partial_cmp(&b.velocity)
+                    .unwrap_or(Ordering::Equal)
+                    .then(a.age.cmp(&b.age))),
+            StealPolicy::SameNote => voices
+                .filter(|(_, v)| Some(v.note) == note)
+                .min_by_key(|(_, v)| v.age)
+                .or(oldest),
+        };
+
+        victim.map(|(idx, _)| idx)
+    }
+
+    pub fn note_off(&mut self, note: u8) {
+        let sustain_pedal = self.sustain_pedal;
+
         for v in self.voices.iter_mut() {
-            if !v.is_active {
-                v.reset();
-                v.is_active = true;
-                v.note = note;
-                v.velocity = velocity as f64 / 127.0;
-                v.osc1.frequency(note2freq(note as f64 + self.osc1_tuning));
-                v.osc2.frequency(note2freq(note as f64 + self.osc2_tuning));
-                v.env.enter_state(Attack);
-                v.filter_env.enter_state(Attack);
-                break;
+            if v.is_active && v.note == note && !v.sustained {
+                if sustain_pedal {
+                    v.sustained = true;
+                } else {
+                    v.env.enter_state(Release);
+                    v.filter_env.enter_state(Release);
+                }
             }
         }
     }
 
-    pub fn note_off(&mut self, note: u8) {
+    /// Releases all notes, notes held by the sustain pedal keep sounding.
+    pub fn all_notes_off(&mut self) {
+        for note in 0..128 {
+            self.note_off(note);
+        }
+    }
+
+    /// Silences all voices immediately.
+    pub fn all_sound_off(&mut self) {
         for v in self.voices.iter_mut() {
-            if v.is_active && v.note == note {
+            v.reset();
+            v.is_active = false;
+        }
+        self.fading.clear();
+    }
+
+    pub fn sustain_pedal(&mut self, down: bool) {
+        self.sustain_pedal = down;
+
+        if !down {
+            for v in self.voices.iter_mut().filter(|v| v.is_active && v.sustained) {
+                v.sustained = false;
                 v.env.enter_state(Release);
                 v.filter_env.enter_state(Release);
             }
         }
     }
 
-    pub fn next(&mut self) -> f64 {
+    /// Sets polyphonic aftertouch of all active voices playing the note.
+    pub fn key_pressure(&mut self, note: u8, pressure: f64) {
+        for v in self.voices.iter_mut().filter(|v| v.is_active && v.note == note) {
+            v.pressure = pressure;
+        }
+    }
+
+    pub fn next(&mut self) -> (f64, f64) {
         let lfo_value = self.lfo.next();
-        let lfo_filter_amount = self.lfo_filter_amount;
+        let vibrato = lfo_value * self.modulation * MODULATION_DEPTH;
+
+        let modulation = Modulation {
+            lfo_value,
+            lfo_filter_amount: self.lfo_filter_amount,
+            pitch_ratio: 2.0f64.powf((self.pitch_bend + vibrato) / 12.0),
+            pressure: self.channel_pressure,
+            pressure_cutoff: self.pressure_cutoff,
+            pressure_amplitude: self.pressure_amplitude,
+            pressure_pitch_mod: self.pressure_pitch_mod,
+            pan: self.pan,
+        };
+
+        /* fade out stolen voices */
+        let (mut l, mut r) = (0.0, 0.0);
+        for (v, gain) in self.fading.iter_mut() {
+            let (x, y) = v.next(&modulation);
+            l += x * *gain;
+            r += y * *gain;
+            *gain -= self.fade_step;
+        }
+        self.fading.retain(|(_, gain)| *gain > 0.0);
 
         /* sum active voices */
-        self.voices.iter_mut()
-            .filter(|v| v.is_active)
-            .map(|v| v.next(lfo_value, lfo_filter_amount))
-            .sum()
+        for v in self.voices.iter_mut().filter(|v| v.is_active) {
+            let (x, y) = v.next(&modulation);
+            l += x;
+            r += y;
+        }
+        self.voices.retain(|v| v.is_active);
+
+        (l, r)
     }
 }
 
+#[derive(Copy, Clone)]
 pub struct Preset {
     pub osc1_waveform: Shape,
     pub osc1_pitch_mod: f64,
@@ -145,6 +387,16 @@ pub struct Preset {
     pub lfo_waveform: Shape,
     pub lfo_frequency: f64,
     pub lfo_filter_mod_amount: f64,
+    /// Filter cutoff modulation at full aftertouch pressure.
+    pub pressure_cutoff: f64,
+    /// Gain added at full aftertouch pressure.
+    pub pressure_amplitude: f64,
+    /// LFO vibrato depth in semitones at full aftertouch pressure.
+    pub pressure_pitch_mod: Semitone,
+    /// Pan offset of the highest and lowest note (0.0 - 1.0).
+    pub key_pan_spread: f64,
+    /// Pan offset of consecutive notes alternating left and right (0.0 - 1.0).
+    pub voice_spread: f64,
 }
 
 impl Preset {
@@ -176,6 +428,11 @@ impl Preset {
             lfo_waveform: rng.gen(),
             lfo_frequency: (rng.gen_range(0.0, 1000.0) as f64).ln(),
             lfo_filter_mod_amount: rng.gen_range(0.0, 0.2),
+            pressure_cutoff: rng.gen_range(0.0, 0.4),
+            pressure_amplitude: rng.gen_range(0.0, 0.5),
+            pressure_pitch_mod: rng.gen_range(0.0, 1.0),
+            key_pan_spread: rng.gen_range(0.0, 0.5),
+            voice_spread: rng.gen_range(0.0, 0.3),
         }
     }
 }
@@ -187,11 +444,25 @@ pub struct Synth {
 
 impl Synth {
     pub fn new(sample_rate: f64) -> Self {
+        Synth::with_polyphony(sample_rate, DEFAULT_POLYPHONY)
+    }
+
+    pub fn with_polyphony(sample_rate: f64, polyphony: usize) -> Self {
         Synth {
-            voices: Voices::new(sample_rate, 128)
+            voices: Voices::new(sample_rate, polyphony)
         }
     }
 
+    pub fn steal_policy(&mut self, policy: StealPolicy) {
+        self.voices.steal_policy = policy;
+    }
+
+    /// Fades out one voice chosen by the steal policy. Returns false when
+    /// no voice is playing.
+    pub fn steal_voice(&mut self) -> bool {
+        self.voices.steal(None).is_some()
+    }
+
     pub fn note_on(&mut self, note: u8, velocity: u8) {
         self.voices.note_on(note, velocity)
     }
@@ -200,13 +471,51 @@ impl Synth {
         self.voices.note_off(note)
     }
 
-    pub fn next(&mut self) -> f64 {
+    /// Returns next stereo sample `(left, right)`.
+    pub fn next(&mut self) -> (f64, f64) {
         self.voices.next()
     }
 
+    /// Sets pan position (-1.0 hard left, 1.0 hard right) of all voices.
+    pub fn pan(&mut self, pan: f64) {
+        self.voices.pan = pan;
+    }
+
+    /// Sets modulation wheel position (0.0 - 1.0).
+    pub fn modulation(&mut self, modulation: f64) {
+        self.voices.modulation = modulation;
+    }
+
+    pub fn sustain_pedal(&mut self, down: bool) {
+        self.voices.sustain_pedal(down)
+    }
+
+    /// Sets channel pressure (0.0 - 1.0) applied to all voices.
+    pub fn channel_pressure(&mut self, pressure: f64) {
+        self.voices.channel_pressure = pressure;
+    }
+
+    /// Sets polyphonic key pressure (0.0 - 1.0) of voices playing the note.
+    pub fn key_pressure(&mut self, note: u8, pressure: f64) {
+        self.voices.key_pressure(note, pressure)
+    }
+
+    /// Bends pitch of all voices by specified amount of semitones.
+    pub fn pitch_bend(&mut self, bend: Semitone) {
+        self.voices.pitch_bend = bend;
+    }
+
+    pub fn all_notes_off(&mut self) {
+        self.voices.all_notes_off()
+    }
+
+    pub fn all_sound_off(&mut self) {
+        self.voices.all_sound_off()
+    }
+
     pub fn voices(&self) -> (usize, usize) {
-        let mut available = self.voices.voices.len();
-        let mut used = self.voices.voices.iter()
+        let available = self.voices.polyphony;
+        let used = self.voices.voices.iter()
             .filter(|x| x.is_active)
             .count();
 
@@ -214,7 +523,8 @@ impl Synth {
     }
 
     pub fn apply_preset(&mut self, preset: &Preset) {
-        for voice in self.voices.voices.iter_mut() {
+        let voices = &mut self.voices;
+        for voice in voices.voices.iter_mut().chain(std::iter::once(&mut voices.template)) {
             voice.osc1.shape = preset.osc1_waveform;
             voice.osc2.shape = preset.osc2_waveform;
             voice.osc1_pitch_mod = preset.osc1_pitch_mod;
@@ -244,5 +554,116 @@ impl Synth {
 
         self.voices.osc1_tuning = preset.osc1_tuning;
         self.voices.osc2_tuning = preset.osc2_tuning;
+
+        self.voices.pressure_cutoff = preset.pressure_cutoff;
+        self.voices.pressure_amplitude = preset.pressure_amplitude;
+        self.voices.pressure_pitch_mod = preset.pressure_pitch_mod;
+
+        self.voices.key_pan_spread = preset.key_pan_spread;
+        self.voices.voice_spread = preset.voice_spread;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::synth::{Synth, StealPolicy, Preset};
+    use crate::bank::gm_preset;
+    use crate::midi::GMInstrument;
+
+    fn notes(synth: &Synth) -> Vec<u8> {
+        synth.voices.voices.iter().map(|v| v.note).collect()
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn steal_policies() {
+        let mut synth = Synth::with_polyphony(44100.0, 2);
+        synth.note_on(60, 100);
+        synth.note_on(62, 20);
+        synth.note_on(64, 100);
+        assert_eq!(notes(&synth), vec![64, 62]);
+        assert_eq!(synth.voices(), (2, 2));
+
+        synth.steal_policy(StealPolicy::LowestVelocity);
+        synth.note_on(65, 100);
+        assert_eq!(notes(&synth), vec![64, 65]);
+
+        synth.steal_policy(StealPolicy::SameNote);
+        synth.note_on(65, 100);
+        assert_eq!(notes(&synth), vec![64, 65]);
+        synth.note_on(67, 100);
+        assert_eq!(notes(&synth), vec![67, 65]);
+
+        /* stolen voices fade out */
+        assert_eq!(synth.voices.fading.len(), 4);
+        for _ in 0..441 {
+            synth.next();
+        }
+        assert!(synth.voices.fading.is_empty());
+
+        /* the newer note is released and gets quieter than the older held one */
+        synth.steal_policy(StealPolicy::Quietest);
+        synth.note_off(67);
+        for _ in 0..441 {
+            synth.next();
+        }
+        assert_eq!(synth.voices(), (2, 2));
+        synth.note_on(69, 100);
+        assert_eq!(notes(&synth), vec![69, 65]);
+    }
+
+    #[test]
+    fn aftertouch() {
+        let preset = Preset {
+            pressure_cutoff: 0.0,
+            pressure_amplitude: 0.5,
+            pressure_pitch_mod: 0.0,
+            ..gm_preset(GMInstrument::new(0).unwrap())
+        };
+        let mut plain = Synth::with_polyphony(44100.0, 2);
+        let mut pressed = Synth::with_polyphony(44100.0, 2);
+        plain.apply_preset(&preset);
+        pressed.apply_preset(&preset);
+
+        /* polyphonic pressure reaches only voices of the note */
+        pressed.note_on(60, 100);
+        pressed.note_on(64, 100);
+        pressed.key_pressure(64, 0.5);
+        let pressures: Vec<f64> = pressed.voices.voices.iter().map(|v| v.pressure).collect();
+        assert_eq!(pressures, vec![0.0, 0.5]);
+
+        /* full channel pressure adds half of the gain */
+        pressed.channel_pressure(1.0);
+        plain.note_on(60, 100);
+        plain.note_on(64, 100);
+        let (mut a, mut b) = (0.0, 0.0);
+        for _ in 0..1000 {
+            a += plain.next().0.abs();
+            b += pressed.next().0.abs();
+        }
+        assert!((b / a - 1.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn voice_panning() {
+        let mut synth = Synth::with_polyphony(44100.0, 4);
+        synth.apply_preset(&Preset {
+            key_pan_spread: 0.5,
+            voice_spread: 0.25,
+            ..gm_preset(GMInstrument::new(0).unwrap())
+        });
+
+        synth.note_on(32, 100);
+        synth.note_on(96, 100);
+        let pans: Vec<f64> = synth.voices.voices.iter().map(|v| v.pan).collect();
+        assert_eq!(pans, vec![0.0, 0.0]);
+
+        synth.note_on(64, 100);
+        assert_eq!(synth.voices.voices[2].pan, 0.25);
+
+        /* synth pan moves all voices, they keep their offsets */
+        synth.pan(-1.0);
+        synth.next();
+        assert!(synth.voices.voices[0].pan_gains.1.abs() < 1e-9);
+        assert_eq!(synth.voices.voices[2].gains_pan, -0.75);
+    }
+}
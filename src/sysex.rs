@@ -0,0 +1,65 @@
+use crate::synth::Semitone;
+
+const UNIVERSAL_NON_REAL_TIME: u8 = 0x7e;
+const UNIVERSAL_REAL_TIME: u8 = 0x7f;
+const ROLAND: u8 = 0x41;
+const YAMAHA: u8 = 0x43;
+
+/// System exclusive message understood by the player.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SysEx {
+    /// General MIDI System On (level 1 or 2).
+    GmSystemOn,
+    GmSystemOff,
+    /// Roland GS Reset.
+    GsReset,
+    /// Yamaha XG System On.
+    XgSystemOn,
+    /// Master volume (0.0 - 1.0).
+    MasterVolume(f64),
+    /// Master fine tuning, -1.0 to 1.0 semitone.
+    MasterFineTuning(Semitone),
+    /// Master coarse tuning, -64 to 63 semitones.
+    MasterCoarseTuning(Semitone),
+}
+
+impl SysEx {
+    /// Parses message data between the `F0` and `F7` bytes, unknown
+    /// messages are `None`. Device id is ignored, all devices respond.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        match *data {
+            [UNIVERSAL_NON_REAL_TIME, _, 0x09, 0x01] | [UNIVERSAL_NON_REAL_TIME, _, 0x09, 0x03] => Some(SysEx::GmSystemOn),
+            [UNIVERSAL_NON_REAL_TIME, _, 0x09, 0x02] => Some(SysEx::GmSystemOff),
+            [ROLAND, _, 0x42, 0x12, 0x40, 0x00, 0x7f, 0x00, _] => Some(SysEx::GsReset),
+            [YAMAHA, device, 0x4c, 0x00, 0x00, 0x7e, 0x00] if device & 0xf0 == 0x10 => Some(SysEx::XgSystemOn),
+            [UNIVERSAL_REAL_TIME, _, 0x04, 0x01, lsb, msb] => Some(SysEx::MasterVolume(u14(lsb, msb) as f64 / 16383.0)),
+            [UNIVERSAL_REAL_TIME, _, 0x04, 0x03, lsb, msb] => Some(SysEx::MasterFineTuning((u14(lsb, msb) as f64 - 8192.0) / 8192.0)),
+            [UNIVERSAL_REAL_TIME, _, 0x04, 0x04, _, msb] => Some(SysEx::MasterCoarseTuning(msb as f64 - 64.0)),
+            _ => None,
+        }
+    }
+}
+
+#[inline]
+fn u14(lsb: u8, msb: u8) -> u16 {
+    ((msb & 0x7f) as u16) << 7 | (lsb & 0x7f) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sysex::SysEx;
+
+    #[test]
+    fn messages() {
+        assert_eq!(SysEx::parse(&[0x7e, 0x7f, 0x09, 0x01]), Some(SysEx::GmSystemOn));
+        assert_eq!(SysEx::parse(&[0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x7f, 0x00, 0x41]), Some(SysEx::GsReset));
+        assert_eq!(SysEx::parse(&[0x43, 0x10, 0x4c, 0x00, 0x00, 0x7e, 0x00]), Some(SysEx::XgSystemOn));
+        assert_eq!(SysEx::parse(&[0x7f, 0x7f, 0x04, 0x01, 0x7f, 0x7f]), Some(SysEx::MasterVolume(1.0)));
+        assert_eq!(SysEx::parse(&[0x7f, 0x7f, 0x04, 0x03, 0x00, 0x60]), Some(SysEx::MasterFineTuning(0.5)));
+        assert_eq!(SysEx::parse(&[0x7f, 0x7f, 0x04, 0x04, 0x00, 0x3e]), Some(SysEx::MasterCoarseTuning(-2.0)));
+
+        /* unknown and truncated messages */
+        assert_eq!(SysEx::parse(&[0x43, 0x00, 0x4c, 0x00, 0x00, 0x7e, 0x00]), None);
+        assert_eq!(SysEx::parse(&[0x7f, 0x7f, 0x04, 0x01, 0x7f]), None);
+    }
+}
@@ -0,0 +1,225 @@
+/// Microseconds per quarter note assumed until the first SetTempo event (120 bpm).
+pub const DEFAULT_MPQN: u32 = 500_000;
+
+/// Time division from the header chunk of a midi file.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TimeDivision {
+    /// Ticks per quarter note, tick length depends on current tempo.
+    Ppqn(u16),
+    /// Subdivisions of a second consistent with SMPTE and MIDI Time Code,
+    /// tick length is constant and tempo changes are ignored.
+    Smpte { fps: f64, ticks_per_frame: u8 },
+}
+
+impl TimeDivision {
+    pub fn new(time_division: u16) -> Self {
+        // If bit 15 of <time_division> is a one, the upper byte holds negative
+        // frames per second and the lower byte holds ticks per frame.
+        if time_division & 0x8000 == 0x8000 {
+            let frames = ((time_division >> 8) as u8 as i8).wrapping_neg() as u8;
+            TimeDivision::Smpte {
+                fps: smpte_fps(frames),
+                ticks_per_frame: time_division as u8,
+            }
+        } else {
+            TimeDivision::Ppqn(time_division)
+        }
+    }
+
+    /// Duration of specified number of ticks in microseconds with specified tempo.
+    #[inline]
+    pub fn ticks_to_micros(&self, ticks: f64, mpqn: u32) -> f64 {
+        match *self {
+            TimeDivision::Ppqn(ppqn) => ticks * mpqn as f64 / ppqn.max(1) as f64,
+            TimeDivision::Smpte { fps, ticks_per_frame } => ticks * 1_000_000.0 / (fps * ticks_per_frame.max(1) as f64),
+        }
+    }
+
+    /// Number of ticks that fit into specified duration with specified tempo.
+    #[inline]
+    pub fn micros_to_ticks(&self, micros: f64, mpqn: u32) -> f64 {
+        match *self {
+            TimeDivision::Ppqn(ppqn) => micros * ppqn.max(1) as f64 / mpqn.max(1) as f64,
+            TimeDivision::Smpte { fps, ticks_per_frame } => micros * fps * ticks_per_frame.max(1) as f64 / 1_000_000.0,
+        }
+    }
+}
+
+/// Returns real frame rate for the nominal SMPTE frame rate. The 29 fps
+/// format is 30 fps drop-frame running at 29.97 frames per second.
+fn smpte_fps(frames: u8) -> f64 {
+    match frames {
+        29 => 30_000.0 / 1001.0,
+        n => n as f64,
+    }
+}
+
+/// Time from the SMPTEOffset meta event at which the track is supposed to start.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SmpteTime {
+    pub fps: f64,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub frames: u8,
+    /// Fractional frames in hundredths of a frame.
+    pub subframes: u8,
+}
+
+impl SmpteTime {
+    /// Parses data of the SMPTEOffset meta event (`hr mn se fr ff`). Frame
+    /// rate is encoded in bits 5-6 of the hour byte.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 5 {
+            return None;
+        }
+
+        Some(SmpteTime {
+            fps: smpte_fps([24, 25, 29, 30][(data[0] >> 5 & 0x3) as usize]),
+            hours: data[0] & 0x1f,
+            minutes: data[1],
+            seconds: data[2],
+            frames: data[3],
+            subframes: data[4],
+        })
+    }
+
+    pub fn to_micros(self) -> f64 {
+        let seconds = self.hours as f64 * 3600.0 + self.minutes as f64 * 60.0 + self.seconds as f64;
+        let frames = self.frames as f64 + self.subframes as f64 / 100.0;
+        (seconds + frames / self.fps) * 1_000_000.0
+    }
+}
+
+/// Single tempo change on the absolute tick timeline.
+#[derive(Debug, Copy, Clone)]
+pub struct TempoChange {
+    pub tick: u64,
+    pub mpqn: u32,
+    /// Time of the change in microseconds from the start of the song.
+    pub time: f64,
+}
+
+impl TempoChange {
+    #[inline]
+    pub fn bpm(&self) -> f64 {
+        60_000_000.0 / self.mpqn as f64
+    }
+}
+
+/// Piecewise-linear mapping between absolute ticks and microseconds
+/// built from SetTempo events of a song or of a format 2 pattern.
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+    division: TimeDivision,
+    changes: Vec<TempoChange>,
+}
+
+impl TempoMap {
+    /// Builds the map from `(tick, mpqn)` pairs. Pairs do not have to be sorted,
+    /// when multiple changes happen on the same tick the last one wins.
+    pub fn new(division: TimeDivision, tempos: &[(u64, u32)]) -> Self {
+        let mut sorted = match division {
+            TimeDivision::Ppqn(_) => tempos.to_vec(),
+            TimeDivision::Smpte { .. } => vec![], /* tick length is absolute */
+        };
+        sorted.sort_by_key(|&(tick, _)| tick); /* stable, keeps file order on same tick */
+
+        let mut changes = vec![TempoChange { tick: 0, mpqn: DEFAULT_MPQN, time: 0.0 }];
+        for (tick, mpqn) in sorted {
+            let last = *changes.last().unwrap();
+            let time = last.time + division.ticks_to_micros((tick - last.tick) as f64, last.mpqn);
+
+            if last.tick == tick {
+                changes.pop();
+            }
+            changes.push(TempoChange { tick, mpqn, time });
+        }
+
+        TempoMap { division, changes }
+    }
+
+    #[inline]
+    pub fn changes(&self) -> &[TempoChange] {
+        &self.changes
+    }
+
+    /// Returns the tempo change in effect at specified tick.
+    fn change_at_tick(&self, tick: u64) -> &TempoChange {
+        let idx = match self.changes.binary_search_by_key(&tick, |c| c.tick) {
+            Ok(idx) => idx,
+            Err(idx) => idx - 1, /* first change is always at tick 0 */
+        };
+        &self.changes[idx]
+    }
+
+    /// Returns the tempo change in effect at specified time in microseconds.
+    fn change_at_time(&self, time: f64) -> &TempoChange {
+        let idx = self.changes.iter()
+            .rposition(|c| c.time <= time)
+            .unwrap_or(0);
+        &self.changes[idx]
+    }
+
+    pub fn tick_to_micros(&self, tick: u64) -> f64 {
+        let change = self.change_at_tick(tick);
+        change.time + self.division.ticks_to_micros((tick - change.tick) as f64, change.mpqn)
+    }
+
+    /// Converts time in microseconds to (fractional) absolute tick.
+    pub fn micros_to_tick(&self, time: f64) -> f64 {
+        let change = self.change_at_time(time);
+        let elapsed = (time - change.time).max(0.0);
+        change.tick as f64 + self.division.micros_to_ticks(elapsed, change.mpqn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tempo::{TempoMap, TimeDivision, SmpteTime};
+
+    #[test]
+    fn default_tempo() {
+        let map = TempoMap::new(TimeDivision::Ppqn(480), &[]);
+        assert_eq!(map.tick_to_micros(480), 500_000.0);
+        assert_eq!(map.micros_to_tick(1_000_000.0), 960.0);
+    }
+
+    #[test]
+    fn tempo_changes() {
+        /* 120 bpm for one beat, then 60 bpm */
+        let map = TempoMap::new(TimeDivision::Ppqn(100), &[(100, 1_000_000), (0, 500_000)]);
+        assert_eq!(map.tick_to_micros(100), 500_000.0);
+        assert_eq!(map.tick_to_micros(200), 1_500_000.0);
+        assert_eq!(map.micros_to_tick(1_500_000.0), 200.0);
+        assert_eq!(map.changes()[1].mpqn, 1_000_000);
+    }
+
+    #[test]
+    fn same_tick_last_wins() {
+        let map = TempoMap::new(TimeDivision::Ppqn(100), &[(0, 250_000), (0, 1_000_000)]);
+        assert_eq!(map.changes().len(), 1);
+        assert_eq!(map.tick_to_micros(100), 1_000_000.0);
+    }
+
+    #[test]
+    fn smpte_division() {
+        /* -25 fps, 40 ticks per frame = millisecond resolution */
+        let division = TimeDivision::new(0xE728);
+        assert_eq!(division, TimeDivision::Smpte { fps: 25.0, ticks_per_frame: 40 });
+
+        /* unknown frame rate -128 does not overflow */
+        assert_eq!(TimeDivision::new(0x8001), TimeDivision::Smpte { fps: 128.0, ticks_per_frame: 1 });
+
+        let map = TempoMap::new(division, &[(0, 1_000_000)]);
+        assert_eq!(map.tick_to_micros(1000), 1_000_000.0);
+    }
+
+    #[test]
+    fn smpte_offset() {
+        /* 01:00:02:12.50 at 25 fps */
+        let offset = SmpteTime::parse(&[0x20 | 1, 0, 2, 12, 50]).unwrap();
+        assert_eq!(offset.to_micros(), 3_602_500_000.0);
+
+    }
+}
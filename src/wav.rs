@@ -0,0 +1,213 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Encoding of samples in the data chunk.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SampleFormat {
+    Pcm16,
+    Pcm24,
+    Float32,
+}
+
+impl SampleFormat {
+    #[inline]
+    pub fn bytes(self) -> u16 {
+        match self {
+            SampleFormat::Pcm16 => 2,
+            SampleFormat::Pcm24 => 3,
+            SampleFormat::Float32 => 4,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct WavSpec {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub format: SampleFormat,
+}
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+
+/// Writes RIFF WAVE file. Sizes in the header are written when the
+/// writer is finalized.
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    spec: WavSpec,
+    frames: u64,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create(path: &Path, spec: WavSpec) -> io::Result<Self> {
+        WavWriter::new(BufWriter::new(File::create(path)?), spec)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, spec: WavSpec) -> io::Result<Self> {
+        let float = spec.format == SampleFormat::Float32;
+        let block_align = spec.channels * spec.format.bytes();
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+
+        /* non-PCM formats need the extension size and the fact chunk */
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&(if float { 18u32 } else { 16u32 }).to_le_bytes())?;
+        writer.write_all(&(if float { WAVE_FORMAT_IEEE_FLOAT } else { WAVE_FORMAT_PCM }).to_le_bytes())?;
+        writer.write_all(&spec.channels.to_le_bytes())?;
+        writer.write_all(&spec.sample_rate.to_le_bytes())?;
+        writer.write_all(&(spec.sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&(spec.format.bytes() * 8).to_le_bytes())?;
+        if float {
+            writer.write_all(&0u16.to_le_bytes())?;
+            writer.write_all(b"fact")?;
+            writer.write_all(&4u32.to_le_bytes())?;
+            writer.write_all(&0u32.to_le_bytes())?;
+        }
+
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter { writer, spec, frames: 0 })
+    }
+
+    #[inline]
+    pub fn spec(&self) -> WavSpec {
+        self.spec
+    }
+
+    #[inline]
+    fn header_size(&self) -> u64 {
+        if self.spec.format == SampleFormat::Float32 { 58 } else { 44 }
+    }
+
+    #[inline]
+    fn data_size(&self, frames: u64) -> u64 {
+        frames * (self.spec.channels * self.spec.format.bytes()) as u64
+    }
+
+    /// Pretends that frames were already written to get close to the size limit.
+    #[cfg(test)]
+    pub fn skip_frames(&mut self, frames: u64) {
+        self.frames += frames;
+    }
+
+    /// Returns whether another frame would exceed the 4 GiB limit of RIFF sizes.
+    pub fn is_full(&self) -> bool {
+        let data_size = self.data_size(self.frames + 1);
+        self.header_size() - 8 + data_size + data_size % 2 > u32::MAX as u64
+    }
+
+    /// Writes one frame, `samples` must contain value (-1.0 - 1.0) for each channel.
+    /// Fails when the file is full.
+    pub fn write_frame(&mut self, samples: &[f64]) -> io::Result<()> {
+        debug_assert_eq!(samples.len(), self.spec.channels as usize);
+
+        if self.is_full() {
+            return Err(io::Error::other("WAV file cannot be larger than 4 GiB"));
+        }
+
+        for &sample in samples {
+            let sample = sample.clamp(-1.0, 1.0);
+            match self.spec.format {
+                SampleFormat::Pcm16 => {
+                    self.writer.write_all(&((sample * 32767.0).round() as i16).to_le_bytes())?
+                }
+                SampleFormat::Pcm24 => {
+                    let bytes = ((sample * 8_388_607.0).round() as i32).to_le_bytes();
+                    self.writer.write_all(&bytes[0..3])?
+                }
+                SampleFormat::Float32 => {
+                    self.writer.write_all(&(sample as f32).to_le_bytes())?
+                }
+            }
+        }
+
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Fills in chunk sizes and flushes the file.
+    pub fn finalize(mut self) -> io::Result<W> {
+        let float = self.spec.format == SampleFormat::Float32;
+        let data_size = self.data_size(self.frames);
+        let header_size = self.header_size();
+
+        /* data chunk is padded to even size */
+        if data_size % 2 == 1 {
+            self.writer.write_all(&[0])?;
+        }
+
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(&((header_size - 8 + data_size + data_size % 2) as u32).to_le_bytes())?;
+        if float {
+            self.writer.seek(SeekFrom::Start(46))?;
+            self.writer.write_all(&(self.frames as u32).to_le_bytes())?;
+        }
+        self.writer.seek(SeekFrom::Start(header_size - 4))?;
+        self.writer.write_all(&(data_size as u32).to_le_bytes())?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wav::{WavWriter, WavSpec, SampleFormat};
+    use std::io::Cursor;
+
+    fn u32_at(bytes: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]])
+    }
+
+    #[test]
+    fn pcm_and_float_headers() {
+        let spec = WavSpec { channels: 2, sample_rate: 44100, format: SampleFormat::Pcm16 };
+        let mut wav = WavWriter::new(Cursor::new(vec![]), spec).unwrap();
+        wav.write_frame(&[1.0, -2.0]).unwrap();
+        let bytes = wav.finalize().unwrap().into_inner();
+
+        assert_eq!(bytes.len(), 48);
+        assert_eq!(u32_at(&bytes, 4), 40);
+        assert_eq!(u32_at(&bytes, 40), 4);
+        assert_eq!(&bytes[44..], &[0xff, 0x7f, 0x01, 0x80]);
+
+        let spec = WavSpec { channels: 1, sample_rate: 48000, format: SampleFormat::Float32 };
+        let mut wav = WavWriter::new(Cursor::new(vec![]), spec).unwrap();
+        wav.write_frame(&[0.5]).unwrap();
+        let bytes = wav.finalize().unwrap().into_inner();
+
+        assert_eq!(bytes.len(), 62);
+        assert_eq!(&bytes[38..42], b"fact");
+        assert_eq!(u32_at(&bytes, 46), 1);
+        assert_eq!(u32_at(&bytes, 54), 4);
+        assert_eq!(&bytes[58..], &0.5f32.to_le_bytes());
+
+        /* odd data size is padded */
+        let spec = WavSpec { channels: 1, sample_rate: 8000, format: SampleFormat::Pcm24 };
+        let mut wav = WavWriter::new(Cursor::new(vec![]), spec).unwrap();
+        wav.write_frame(&[0.0]).unwrap();
+        let bytes = wav.finalize().unwrap().into_inner();
+        assert_eq!(bytes.len(), 48);
+        assert_eq!(u32_at(&bytes, 4), 40);
+        assert_eq!(u32_at(&bytes, 40), 3);
+    }
+
+    #[test]
+    fn size_limit() {
+        /* 4 bytes per frame, the RIFF size may not exceed u32::MAX */
+        let spec = WavSpec { channels: 2, sample_rate: 44100, format: SampleFormat::Pcm16 };
+        let mut wav = WavWriter::new(Cursor::new(vec![]), spec).unwrap();
+        wav.frames = (u32::MAX as u64 - 36) / 4 - 1;
+        assert!(wav.write_frame(&[0.0, 0.0]).is_ok());
+        assert!(wav.write_frame(&[0.0, 0.0]).is_err());
+        assert_eq!(wav.frames, (u32::MAX as u64 - 36) / 4);
+    }
+}